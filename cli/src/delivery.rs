@@ -0,0 +1,79 @@
+use hyper::Method;
+
+pub struct Delivery {
+    pub method: Method,
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum DeliveryParseError {
+    MissingBody,
+    InvalidHeaders,
+    InvalidRequestLine,
+}
+
+impl std::error::Error for DeliveryParseError {}
+
+impl std::fmt::Display for DeliveryParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryParseError::MissingBody => {
+                write!(f, "no blank line separating headers from body")
+            }
+            DeliveryParseError::InvalidHeaders => write!(f, "headers are not valid utf-8"),
+            DeliveryParseError::InvalidRequestLine => write!(f, "invalid request line"),
+        }
+    }
+}
+
+/// Parses a raw recorded request: an optional `METHOD /path HTTP/1.1` request line,
+/// `Name: value` headers, a blank line, and then the body bytes exactly as received.
+pub fn parse(bytes: &[u8]) -> Result<Delivery, DeliveryParseError> {
+    let (head, body) = split_head(bytes).ok_or(DeliveryParseError::MissingBody)?;
+    let head = std::str::from_utf8(head).map_err(|_| DeliveryParseError::InvalidHeaders)?;
+
+    let mut lines = head.lines().map(|l| l.trim_end_matches('\r')).peekable();
+
+    let mut method = Method::POST;
+    let mut path = None;
+
+    if let Some(first) = lines.peek() {
+        if !first.contains(':') || first.contains(" HTTP/") {
+            let mut parts = first.split_whitespace();
+            method = parts
+                .next()
+                .and_then(|m| m.parse().ok())
+                .ok_or(DeliveryParseError::InvalidRequestLine)?;
+            path = parts.next().map(String::from);
+            lines.next();
+        }
+    }
+
+    let headers = lines
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let (name, value) = l
+                .split_once(':')
+                .ok_or(DeliveryParseError::InvalidHeaders)?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Delivery {
+        method,
+        path,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+fn split_head(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    for (sep, len) in [(&b"\r\n\r\n"[..], 4), (&b"\n\n"[..], 2)] {
+        if let Some(pos) = bytes.windows(len).position(|w| w == sep) {
+            return Some((&bytes[..pos], &bytes[pos + len..]));
+        }
+    }
+    None
+}
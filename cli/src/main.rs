@@ -0,0 +1,213 @@
+mod delivery;
+
+use std::io::Read;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use hyper::{Body, Client, Request};
+
+use config::SignatureAlgorithm;
+
+#[derive(Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Send a recorded raw delivery to a server, preserving its original signature
+    Replay {
+        delivery: PathBuf,
+        #[clap(long, default_value = "http://127.0.0.1:4050")]
+        target: String,
+        /// Overrides the path from the recorded request line
+        #[clap(long)]
+        path: Option<String>,
+        /// Client key to send as Basic auth, for captures with a redacted Authorization header
+        #[clap(long)]
+        client: Option<String>,
+        /// Verify the response's X-Webhook-Response-Signature with this secret
+        #[clap(long)]
+        secret: Option<String>,
+    },
+    /// Print the signature the server expects for a payload, as sent in X-Hub-Signature-256
+    Sign {
+        /// File holding the exact request body, `-` for stdin
+        payload: PathBuf,
+        #[clap(long)]
+        secret: String,
+        #[clap(long, default_value = "sha256")]
+        algorithm: SignatureAlgorithm,
+        /// Check this signature instead, its algorithm is taken from its prefix
+        #[clap(long)]
+        verify: Option<String>,
+    },
+}
+
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length", "transfer-encoding", "connection"];
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let res = match args.command {
+        CliCommand::Replay {
+            delivery,
+            target,
+            path,
+            client,
+            secret,
+        } => replay(delivery, target, path, client, secret).await,
+        CliCommand::Sign {
+            payload,
+            secret,
+            algorithm,
+            verify,
+        } => sign(payload, secret, algorithm, verify),
+    };
+
+    if let Err(err) = res {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// Uses the same code as the server, so a mismatch here is a mismatch there.
+fn sign(
+    payload: PathBuf,
+    secret: String,
+    algorithm: SignatureAlgorithm,
+    verify: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let body = if payload.as_os_str() == "-" {
+        let mut body = Vec::new();
+        std::io::stdin().read_to_end(&mut body)?;
+        body
+    } else {
+        std::fs::read(&payload)?
+    };
+
+    match verify {
+        Some(signature) if config::signature::verify(&secret, &body, &signature) => {
+            println!("signature matches");
+            Ok(())
+        }
+        Some(signature) => {
+            let algorithm = signature
+                .split_once('=')
+                .and_then(|(prefix, _)| prefix.parse().ok())
+                .unwrap_or(algorithm);
+            Err(format!(
+                "signature does not match, expected {}",
+                config::signature::expected_signature(algorithm, &secret, &body)
+            )
+            .into())
+        }
+        None => {
+            println!(
+                "{}",
+                config::signature::expected_signature(algorithm, &secret, &body)
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn replay(
+    delivery: PathBuf,
+    target: String,
+    path: Option<String>,
+    client: Option<String>,
+    secret: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(&delivery)?;
+    let delivery = delivery::parse(&bytes)?;
+
+    let path = path
+        .or(delivery.path)
+        .unwrap_or_else(|| String::from("/deploy"));
+    let uri = format!("{}{}", target.trim_end_matches('/'), path);
+
+    let mut req = Request::builder().method(delivery.method).uri(&uri);
+    for (name, value) in delivery.headers.iter() {
+        let name_lower = name.to_ascii_lowercase();
+        let replaced = client.is_some() && name_lower == "authorization";
+        if !replaced && !SKIPPED_HEADERS.contains(&name_lower.as_str()) {
+            req = req.header(name, value);
+        }
+    }
+    if let Some(client) = client {
+        req = req.header(
+            "authorization",
+            format!("Basic {}", base64::encode(format!("{client}:"))),
+        );
+    }
+    let req = req.body(Body::from(delivery.body))?;
+
+    println!("replaying delivery to: {uri}");
+
+    let res = Client::new().request(req).await?;
+    let status = res.status();
+    let signature = res
+        .headers()
+        .get("x-webhook-response-signature")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+
+    println!("{status}");
+    if !body.is_empty() {
+        println!("{}", String::from_utf8_lossy(&body));
+    }
+
+    if let Some(secret) = secret {
+        match signature {
+            Some(signature) if config::signature::verify(&secret, &body, &signature) => {
+                println!("response signature verified")
+            }
+            Some(_) => return Err("response signature does not match".into()),
+            None => return Err("response is not signed".into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example from GitHub's webhook docs.
+    const SECRET: &str = "It's a Secret to Everybody";
+    const PAYLOAD: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn signature_matches_githubs_example() {
+        assert_eq!(
+            config::signature::expected_signature(SignatureAlgorithm::Sha256, SECRET, PAYLOAD),
+            SIGNATURE
+        );
+    }
+
+    #[test]
+    fn sign_verifies_githubs_example() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = dir.path().join("payload");
+        std::fs::write(&payload, PAYLOAD).unwrap();
+        let verify = |signature: &str| {
+            sign(
+                payload.clone(),
+                SECRET.to_string(),
+                SignatureAlgorithm::Sha256,
+                Some(signature.to_string()),
+            )
+        };
+
+        assert!(verify(SIGNATURE).is_ok());
+        let err = verify("sha256=00").unwrap_err();
+        assert!(err.to_string().ends_with(SIGNATURE), "{err}");
+    }
+}
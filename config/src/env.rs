@@ -0,0 +1,91 @@
+use crate::ConfigError;
+
+/// Replaces `${VAR}` in every string in the config with the variable's value,
+/// so secrets can live in the environment rather than the file. `$${` is a
+/// literal `${`. Table keys are left alone.
+pub fn expand(value: &mut toml::Value) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::String(s) => *s = expand_str(s)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                expand(value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand(value)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn expand_str(s: &str) -> Result<String, ConfigError> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let (name, after) = after
+                .split_once('}')
+                .ok_or(ConfigError::UnterminatedEnvVar)?;
+            let value =
+                std::env::var(name).map_err(|_| ConfigError::UnsetEnvVar(name.to_string()))?;
+            expanded.push_str(&value);
+            rest = after;
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_variable_is_expanded() {
+        std::env::set_var("WEBHOOKS_TEST_SECRET", "from-env");
+        assert_eq!(
+            expand_str("s-${WEBHOOKS_TEST_SECRET}-$HOME").unwrap(),
+            "s-from-env-$HOME"
+        );
+
+        let mut value: toml::Value =
+            toml::from_str("[clients.abc]\nsecret = \"${WEBHOOKS_TEST_SECRET}\"").unwrap();
+        expand(&mut value).unwrap();
+        assert_eq!(value["clients"]["abc"]["secret"].as_str(), Some("from-env"));
+    }
+
+    #[test]
+    fn unset_variable_is_an_error() {
+        std::env::remove_var("WEBHOOKS_TEST_UNSET");
+        assert!(matches!(
+            expand_str("${WEBHOOKS_TEST_UNSET}"),
+            Err(ConfigError::UnsetEnvVar(name)) if name == "WEBHOOKS_TEST_UNSET"
+        ));
+        assert!(matches!(
+            expand_str("${WEBHOOKS_TEST_UNSET"),
+            Err(ConfigError::UnterminatedEnvVar)
+        ));
+    }
+
+    #[test]
+    fn escaped_variable_is_a_literal() {
+        assert_eq!(
+            expand_str("$${WEBHOOKS_TEST_UNSET}").unwrap(),
+            "${WEBHOOKS_TEST_UNSET}"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::Command;
+
+/// Commands the server has dispatched but dispatch has not yet run, so a
+/// dispatch restart loses nothing that was still waiting in the pipe.
+///
+/// The journal holds one wire command per line. The server appends each
+/// command before sending it, and dispatch removes it once it has run by
+/// rewriting the journal without it. Both take an exclusive lock on a
+/// `.lock` file next to the journal first, the journal itself is replaced on
+/// every removal and cannot be locked.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+    lock: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Self {
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+
+        Journal {
+            path: path.to_path_buf(),
+            lock: PathBuf::from(lock),
+        }
+    }
+
+    /// Unique across restarts of the server and between servers on one host.
+    pub fn next_id() -> String {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or_default();
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        format!("{nanos:x}-{:x}-{count:x}", std::process::id())
+    }
+
+    /// Durably records the command, which must have a `journal_id`.
+    pub fn append(&self, command: &Command) -> io::Result<()> {
+        let _lock = self.lock()?;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        journal.write_all(format!("{}\n", command.to_wire()).as_bytes())?;
+        journal.sync_data()
+    }
+
+    /// Drops the command with `id`, whether it ran or was never sent.
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        let _lock = self.lock()?;
+        let lines = self.read_lines()?;
+        let remaining = lines
+            .iter()
+            .filter(|line| journal_id(line).as_deref() != Some(id));
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        for line in remaining {
+            writeln!(file, "{line}")?;
+        }
+        file.sync_data()?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Every command still waiting to be run, oldest first.
+    pub fn pending(&self) -> io::Result<Vec<Command>> {
+        let _lock = self.lock()?;
+        Ok(self
+            .read_lines()?
+            .iter()
+            .filter_map(|line| Command::from_wire(line).ok())
+            .collect())
+    }
+
+    fn read_lines(&self) -> io::Result<Vec<String>> {
+        match File::open(&self.path) {
+            Ok(file) => BufReader::new(file).lines().collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Held until the returned file is dropped.
+    fn lock(&self) -> io::Result<File> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock)?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(lock)
+    }
+}
+
+/// Just the id of a journalled command, so removing one doesn't decode the
+/// payload of every other.
+#[derive(Deserialize)]
+struct JournalEntry<'a> {
+    #[serde(borrow, default)]
+    journal_id: Option<Cow<'a, str>>,
+}
+
+fn journal_id(line: &str) -> Option<Cow<'_, str>> {
+    serde_json::from_str::<JournalEntry>(line).ok()?.journal_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Action;
+
+    fn journalled(project: &str) -> Command {
+        let mut command = Command::new(Action::Deploy, project).unwrap();
+        command.journal_id = Some(Journal::next_id());
+        command.payload = Some(b"{\"ref\": \"refs/heads/main\"}".to_vec());
+        command
+    }
+
+    #[test]
+    fn restart_replays_pending_entries_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let (ran, waiting) = (journalled("ran"), journalled("waiting"));
+
+        let journal = Journal::new(&path);
+        journal.append(&ran).unwrap();
+        journal.append(&waiting).unwrap();
+        journal.remove(ran.journal_id.as_deref().unwrap()).unwrap();
+
+        let restarted = Journal::new(&path);
+        let pending = restarted.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].project, "waiting");
+        assert_eq!(pending[0].payload, waiting.payload);
+        restarted
+            .remove(pending[0].journal_id.as_deref().unwrap())
+            .unwrap();
+
+        assert!(Journal::new(&path).pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn id_is_read_without_the_rest_of_the_command() {
+        let command = journalled("proj");
+        let id = command.journal_id.clone();
+
+        assert_eq!(journal_id(&command.to_wire()).as_deref(), id.as_deref());
+        assert_eq!(journal_id("deploy proj"), None);
+    }
+}
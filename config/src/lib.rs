@@ -1,73 +1,1134 @@
-use serde::Deserialize;
+mod env;
+pub mod journal;
+pub mod logging;
+pub mod signature;
+pub mod startup;
+pub mod throttle;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, HashSet},
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
 };
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Config {
+    pub version: u32,
     pub webhooks: WebHookConfig,
     pub dispatch: DispatchConfig,
+    #[serde(default)]
+    pub projects: HashMap<String, ProjectConfig>,
     pub clients: HashMap<String, ClientConfig>,
 }
 
-#[derive(Deserialize)]
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Replaced in a client's project with the payload's repository name.
+pub const REPO_PLACEHOLDER: &str = "{repo}";
+
+/// Brings a config file written for any supported older version up to the
+/// shape of the current structs.
+fn migrate(value: toml::Value) -> Result<toml::Value, ConfigError> {
+    let version = value
+        .get("version")
+        .ok_or(ConfigError::MissingVersion)?
+        .as_integer()
+        .ok_or(ConfigError::MissingVersion)?;
+
+    match version {
+        1 => Ok(value),
+        version => Err(ConfigError::UnsupportedVersion(version)),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Parse(toml::de::Error),
+    ParseJson(serde_json::Error),
+    ParseYaml(serde_yaml::Error),
+    MissingVersion,
+    UnsupportedVersion(i64),
+    EmptySecret(String),
+    DuplicateProject {
+        project: String,
+        clients: (String, String),
+    },
+    NoPermissions(String),
+    NoProjects(String),
+    InvalidProjectName(String),
+    NoScriptsDir,
+    EmptyAdminSecret,
+    UnsetEnvVar(String),
+    UnterminatedEnvVar,
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Parse(err) => write!(f, "unable to parse config: {}", err),
+            ConfigError::ParseJson(err) => write!(f, "unable to parse config: {}", err),
+            ConfigError::ParseYaml(err) => write!(f, "unable to parse config: {}", err),
+            ConfigError::MissingVersion => write!(
+                f,
+                "config is missing the required integer `version` field, the current version is {}",
+                CONFIG_VERSION
+            ),
+            ConfigError::UnsupportedVersion(version) if *version > CONFIG_VERSION as i64 => {
+                write!(
+                    f,
+                    "config version {} is newer than the supported version {}",
+                    version, CONFIG_VERSION
+                )
+            }
+            ConfigError::UnsupportedVersion(version) => {
+                write!(f, "config version {} is not supported", version)
+            }
+            ConfigError::EmptySecret(client) => {
+                write!(f, "client `{}` has an empty secret", client)
+            }
+            ConfigError::DuplicateProject { project, clients } => write!(
+                f,
+                "clients `{}` and `{}` are both for project `{}`",
+                clients.0, clients.1, project
+            ),
+            ConfigError::NoPermissions(client) => write!(
+                f,
+                "client `{}` has no permissions, set them on the client or its project",
+                client
+            ),
+            ConfigError::NoProjects(client) => {
+                write!(f, "client `{}` has no projects", client)
+            }
+            ConfigError::InvalidProjectName(project) => {
+                write!(f, "project `{}` is empty or contains whitespace", project)
+            }
+            ConfigError::NoScriptsDir => write!(f, "dispatch `scripts_dir` is an empty list"),
+            ConfigError::EmptyAdminSecret => write!(f, "webhooks `admin_secret` is empty"),
+            ConfigError::UnsetEnvVar(name) => {
+                write!(f, "config refers to unset environment variable `{}`", name)
+            }
+            ConfigError::UnterminatedEnvVar => {
+                write!(
+                    f,
+                    "config has a `${{` without a closing `}}`, use `$${{` for a literal"
+                )
+            }
+        }
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Parse(err)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct WebHookConfig {
     pub pipe: PathBuf,
-    pub listen_addr: std::net::Ipv4Addr,
+    #[serde(flatten)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    pub listen_addr: std::net::IpAddr,
     pub listen_port: u16,
+    #[serde(default)]
+    pub capture_dir: Option<PathBuf>,
+    #[serde(default = "default_capture_max_files")]
+    pub capture_max_files: usize,
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Answer unauthenticated requests with a 401 from the middleware instead
+    /// of passing them on for the handler's extractor to reject.
+    #[serde(default = "default_true")]
+    pub reject_unauthenticated: bool,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+    /// On a signature mismatch, log whether a normalized body (newlines added,
+    /// removed or converted) would have matched, to track down proxies that
+    /// alter bodies. Normalized matches are never accepted.
+    #[serde(default)]
+    pub signature_diagnostics: bool,
+    /// Pipe write handles are kept open between dispatches and closed once
+    /// unused for this long, 0 closes them after every write.
+    #[serde(default = "default_pipe_idle_timeout_secs")]
+    pub pipe_idle_timeout_secs: u64,
+    /// Commands waiting on each pipe's writer past this many are refused with
+    /// a 503 rather than queued.
+    #[serde(default = "default_dispatch_queue_len")]
+    pub dispatch_queue_len: usize,
+    /// Connections past this many are left unaccepted until one closes.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Webhook requests taking longer than this, body included, are answered
+    /// with a 408. Requests waiting on their script with `?wait=true` count.
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+    /// Webhook requests arriving while this many are being handled are
+    /// refused with a 503.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// On SIGTERM or ctrl-c, how long open requests get to finish.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// Requests for the same project, action and environment within this many
+    /// seconds of a dispatch are answered with `{"coalesced": true}` instead of
+    /// being dispatched again.
+    #[serde(default)]
+    pub coalesce_window_secs: Option<u64>,
+    /// How many `X-GitHub-Delivery` ids to remember, an authenticated request
+    /// repeating one is answered with `{"duplicate": true}` and not
+    /// dispatched. 0 turns replay protection off.
+    #[serde(default = "default_replay_cache_size")]
+    pub replay_cache_size: usize,
+    #[serde(default = "default_replay_ttl_secs")]
+    pub replay_ttl_secs: u64,
+    /// Addresses or CIDR ranges of reverse proxies whose `X-Forwarded-For` is
+    /// believed when checking a client's `allowed_cidrs`.
+    #[serde(default, with = "cidrs")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// How many recent webhook requests `GET /deliveries` lists, 0 keeps
+    /// none.
+    #[serde(default = "default_delivery_history_size")]
+    pub delivery_history_size: usize,
+    /// Bearer token for `POST /trigger` and `GET /deliveries`, which are
+    /// disabled when unset.
+    #[serde(
+        default,
+        serialize_with = "redacted",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub admin_secret: Option<String>,
 }
 
-#[derive(Deserialize)]
+/// GitHub caps webhook payloads at 25 MB.
+fn default_max_body_bytes() -> u64 {
+    25 * 1024 * 1024
+}
+
+fn default_pipe_idle_timeout_secs() -> u64 {
+    30
+}
+
+fn default_dispatch_queue_len() -> usize {
+    64
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_replay_cache_size() -> usize {
+    10_000
+}
+
+fn default_delivery_history_size() -> usize {
+    100
+}
+
+fn default_replay_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_capture_max_files() -> usize {
+    100
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SinkConfig {
+    pub pipe: PathBuf,
+    /// Defaults to `webhooks.transport`.
+    #[serde(default)]
+    pub transport: Option<Transport>,
+    #[serde(default = "default_sink_weight")]
+    pub weight: u32,
+}
+
+fn default_sink_weight() -> u32 {
+    1
+}
+
+/// A file a binary logs to as well as stderr, set in the `webhooks` and
+/// `dispatch` sections as each process needs a file of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    #[serde(default)]
+    pub log_rotation: LogRotation,
+    /// With `size` rotation, the size the file is rotated at.
+    #[serde(default = "default_log_max_bytes")]
+    pub log_max_bytes: u64,
+    /// How many rotated files are kept besides the current one.
+    #[serde(default = "default_log_keep_files")]
+    pub log_keep_files: usize,
+}
+
+fn default_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_keep_files() -> usize {
+    5
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// The file grows forever, for an external logrotate to deal with.
+    #[default]
+    Never,
+    /// A new file each day, named `log_file` with the date appended.
+    Daily,
+    /// Once over `log_max_bytes` the file is moved to `log_file.1`, shifting
+    /// older ones up.
+    Size,
+}
+
+/// How commands get from the server to `dispatch`, both sides must agree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// `pipe` is a fifo the server opens for appending, which blocks until
+    /// `dispatch` has it open for reading.
+    #[default]
+    Fifo,
+    /// `pipe` is a unix socket `dispatch` listens on, connecting fails
+    /// straight away when it is not running.
+    Unix,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct DispatchConfig {
     pub pipe: PathBuf,
-    pub scripts_dir: PathBuf,
+    #[serde(flatten)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub transport: Transport,
+    /// A single directory or a list searched in order, the first holding a
+    /// script for the command is the one used.
+    #[serde(rename = "scripts_dir", deserialize_with = "one_or_many")]
+    pub scripts_dirs: Vec<PathBuf>,
+    /// Where scripts run, `{project}` is replaced with the command's project.
+    /// Defaults to the project's directory in the first `scripts_dir` that
+    /// has one.
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// User name or uid scripts run as, dispatch must be running as root.
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    /// Group name or gid scripts run as, defaults to the user's primary group.
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+    #[serde(default)]
+    pub allocate_pty: bool,
+    #[serde(default)]
+    pub script_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub forward_headers: Vec<String>,
+    #[serde(default)]
+    pub event_sink_url: Option<String>,
+    /// Posted a JSON summary whenever a script exits non-zero or times out.
+    #[serde(default)]
+    pub failure_webhook_url: Option<String>,
+    /// Overrides `Action::default_priority` for queued commands, higher runs first.
+    #[serde(default)]
+    pub priorities: HashMap<Action, u8>,
+    /// Scripts running at once across all projects, further commands queue.
+    /// Unlimited when unset, 1 runs one script at a time.
+    #[serde(default)]
+    pub max_concurrent_scripts: Option<NonZeroUsize>,
+    /// Request bodies up to this size travel with the command and are written
+    /// to the script's stdin, larger ones are dropped with a warning.
+    #[serde(default = "default_max_payload_bytes")]
+    pub max_payload_bytes: usize,
+    /// Script output past this many bytes per stream is dropped from the logs.
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// How long the server waits to open and write the dispatch pipe,
+    /// retries included.
+    #[serde(default = "default_dispatch_timeout_ms")]
+    pub dispatch_timeout_ms: u64,
+    /// How many times a write failing with a broken pipe or a full queue is
+    /// tried before giving up, 1 never retries.
+    #[serde(default = "default_dispatch_attempts")]
+    pub dispatch_attempts: u32,
+    /// The wait before the first retry, doubling after each one.
+    #[serde(default = "default_dispatch_retry_delay_ms")]
+    pub dispatch_retry_delay_ms: u64,
+    /// Identical commands arriving within this many milliseconds of each
+    /// other are held until the window is quiet and only the last is sent.
+    /// 0 disables debouncing.
+    #[serde(default)]
+    pub debounce_ms: u64,
+    /// On SIGTERM or SIGINT, how long running scripts get to finish before
+    /// dispatch exits anyway.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// Commands are recorded here until they have run, and replayed when
+    /// dispatch starts. See `journal::Journal`.
+    #[serde(default)]
+    pub journal: Option<PathBuf>,
+    /// A command whose delivery id dispatch already took within this many
+    /// seconds is skipped. The ids are kept next to the journal, so with one
+    /// configured this holds across restarts. 0 turns the check off.
+    #[serde(default = "default_redelivery_window_secs")]
+    pub redelivery_window_secs: u64,
+}
+
+fn default_redelivery_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_payload_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_output_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_dispatch_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_dispatch_attempts() -> u32 {
+    3
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_dispatch_retry_delay_ms() -> u64 {
+    50
+}
+
+impl DispatchConfig {
+    pub fn working_dir(&self, project: &str) -> PathBuf {
+        match self.working_dir.as_deref() {
+            Some(template) => PathBuf::from(template.replace("{project}", project)),
+            None => self
+                .project_scripts_dir(project)
+                .unwrap_or_else(|| self.scripts_dirs[0].join(project)),
+        }
+    }
+
+    /// The project's directory in the first of `scripts_dirs` that has one.
+    pub fn project_scripts_dir(&self, project: &str) -> Option<PathBuf> {
+        self.scripts_dirs
+            .iter()
+            .map(|root| root.join(project))
+            .find(|dir| dir.is_dir())
+    }
+
+    pub fn priority(&self, action: Action) -> u8 {
+        self.priorities
+            .get(&action)
+            .copied()
+            .unwrap_or_else(|| action.default_priority())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProjectConfig {
+    /// A list of actions, or `"all"` for every action.
+    #[serde(
+        default,
+        serialize_with = "sorted",
+        deserialize_with = "permissions::deserialize"
+    )]
+    pub permissions: HashSet<Action>,
+    /// Laid out like `dispatch.scripts_dir`, scripts found here run alongside
+    /// the real ones but their results are only logged.
+    #[serde(default)]
+    pub shadow_scripts_dir: Option<PathBuf>,
+    /// Run before every command for the project, a non-zero exit aborts it.
+    #[serde(default)]
+    pub pre_dispatch_hook: Option<PathBuf>,
+    #[serde(default = "default_pre_dispatch_timeout_secs")]
+    pub pre_dispatch_timeout_secs: u64,
+    /// Passed to every script run for the project, `{project}` and `{action}`
+    /// are replaced with the command's.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+fn default_pre_dispatch_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
+    /// The key the client is configured under.
+    #[serde(skip)]
+    pub name: String,
+    #[serde(serialize_with = "redacted")]
     pub secret: String,
-    pub project: String,
+    /// A single project name or a list, requests for a client with several
+    /// must name the project in an `X-Deploy-Project` header. A project
+    /// containing `{repo}` is filled in from the payload's repository name.
+    #[serde(rename = "project", deserialize_with = "one_or_many")]
+    pub projects: Vec<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(
+        default,
+        rename = "permissions",
+        skip_serializing,
+        deserialize_with = "permissions::deserialize_some"
+    )]
+    declared_permissions: Option<HashSet<Action>>,
+    /// The client's own `permissions` if set, otherwise its project's defaults.
+    #[serde(skip_deserializing, serialize_with = "sorted")]
     pub permissions: HashSet<Action>,
+    #[serde(default)]
+    pub signature_algorithm: SignatureAlgorithm,
+    /// Overrides the header the algorithm's signature is normally sent in.
+    #[serde(default)]
+    pub signature_header: Option<String>,
+    #[serde(default)]
+    pub tls_client_name: Option<String>,
+    #[serde(default)]
+    pub provider: Provider,
+    /// Reject authenticated requests without an `X-GitHub-Event` header, only
+    /// checked for GitHub clients.
+    #[serde(default)]
+    pub require_github_event_header: bool,
+    /// Environments this client may request a deploy to, requests that do not
+    /// name an environment are always allowed.
+    #[serde(default, serialize_with = "sorted")]
+    pub environments: HashSet<String>,
+    /// Sign response bodies with the client's secret and algorithm in an
+    /// `X-Webhook-Response-Signature` header.
+    #[serde(default)]
+    pub sign_responses: bool,
+    /// Overrides `webhooks.max_body_bytes` for this client.
+    #[serde(default)]
+    pub max_body_bytes: Option<u64>,
+    /// Addresses or CIDR ranges authenticated requests must come from, empty
+    /// allows any.
+    #[serde(default, with = "cidrs")]
+    pub allowed_cidrs: Vec<IpNet>,
+    /// Authenticated requests past this rate are answered with a 429.
+    #[serde(default)]
+    pub rate_per_minute: Option<NonZeroU32>,
+    /// How many requests may arrive at once before `rate_per_minute` applies,
+    /// defaults to a minute's worth.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Overrides `dispatch.dispatch_timeout_ms` for this client.
+    #[serde(default)]
+    pub dispatch_timeout_ms: Option<u64>,
+    /// `X-GitHub-Event` values that are dispatched, others are answered with
+    /// a 204. Empty allows every event except `ping`, which is never dispatched.
+    #[serde(default, serialize_with = "sorted")]
+    pub events: HashSet<String>,
+    /// Only dispatch pushes whose payload `ref` matches, e.g.
+    /// `refs/heads/main` or `refs/tags/v*`. `*` matches any run of characters.
+    #[serde(default)]
+    pub ref_filter: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureAlgorithm {
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+    /// GitLab's shared secret, sent as is rather than signing the body.
+    #[serde(rename = "gitlab_token")]
+    GitlabToken,
+}
+
+impl SignatureAlgorithm {
+    pub fn default_header(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Sha1 => "x-hub-signature",
+            SignatureAlgorithm::GitlabToken => "x-gitlab-token",
+            _ => "x-hub-signature-256",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SignatureAlgorithmParseError(String);
+
+impl std::fmt::Display for SignatureAlgorithmParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown signature algorithm: {}", self.0)
+    }
+}
+
+impl std::error::Error for SignatureAlgorithmParseError {}
+
+/// Takes the names used in the config file.
+impl std::str::FromStr for SignatureAlgorithm {
+    type Err = SignatureAlgorithmParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(SignatureAlgorithm::Sha1),
+            "sha256" => Ok(SignatureAlgorithm::Sha256),
+            "sha512" => Ok(SignatureAlgorithm::Sha512),
+            "blake3" => Ok(SignatureAlgorithm::Blake3),
+            "gitlab_token" => Ok(SignatureAlgorithm::GitlabToken),
+            _ => Err(SignatureAlgorithmParseError(s.to_string())),
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Deserialize)]
+/// The service sending webhooks for a client, for checks that only make
+/// sense for deliveries from a particular sender.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    Generic,
+    GitHub,
+}
+
+impl ClientConfig {
+    pub fn display_name(&self) -> &str {
+        self.display_name
+            .as_deref()
+            .or_else(|| self.projects.first().map(String::as_str))
+            .unwrap_or_default()
+    }
+
+    pub fn signature_header(&self) -> &str {
+        self.signature_header
+            .as_deref()
+            .unwrap_or_else(|| self.signature_algorithm.default_header())
+    }
+
+    /// Whether the client is for `project`, directly or through a template.
+    pub fn has_project(&self, project: &str) -> bool {
+        self.projects
+            .iter()
+            .any(|p| p == project || fills_template(p, project))
+    }
+}
+
+/// Whether `project` is `template` with its `{repo}` filled in by a plain
+/// name, one that can't step outside a directory.
+pub fn fills_template(template: &str, project: &str) -> bool {
+    let (prefix, suffix) = match template.split_once(REPO_PLACEHOLDER) {
+        Some(parts) => parts,
+        None => return false,
+    };
+    project
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_suffix(suffix))
+        .is_some_and(|repo| {
+            !repo.is_empty()
+                && !repo.starts_with('.')
+                && repo
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(item) => vec![item],
+        OneOrMany::Many(items) => items,
+    })
+}
+
+/// The formats a config file may be written in, all read into the same
+/// structs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picked by extension, anything unrecognised is read as TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+impl Config {
+    /// Other formats are read into a TOML value first, so migration and
+    /// defaults work the same whatever the format.
+    pub fn parse(contents: &str, format: ConfigFormat) -> Result<Config, ConfigError> {
+        let mut value: toml::Value = match format {
+            ConfigFormat::Toml => toml::from_str(contents)?,
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(ConfigError::ParseJson)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(ConfigError::ParseYaml)?,
+        };
+        env::expand(&mut value)?;
+        let value = migrate(value)?;
+
+        let mut config: Config = value.try_into()?;
+        for (name, client) in config.clients.iter_mut() {
+            client.name = name.clone();
+        }
+        config.resolve_permissions();
+        Ok(config)
+    }
+
+    /// Checks for mistakes that parse fine but would only surface once a
+    /// request arrives.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.webhooks.admin_secret.as_deref() == Some("") {
+            return Err(ConfigError::EmptyAdminSecret);
+        }
+        if self.dispatch.scripts_dirs.is_empty() {
+            return Err(ConfigError::NoScriptsDir);
+        }
+
+        let mut names: Vec<_> = self.clients.keys().collect();
+        names.sort();
+
+        let mut all_projects: Vec<&String> = self
+            .clients
+            .values()
+            .flat_map(|client| client.projects.iter())
+            .chain(self.projects.keys())
+            .collect();
+        all_projects.sort();
+        if let Some(project) = all_projects
+            .into_iter()
+            .find(|project| !is_valid_project_name(project))
+        {
+            return Err(ConfigError::InvalidProjectName(project.clone()));
+        }
+
+        let mut projects: HashMap<&str, &str> = HashMap::new();
+        for name in names {
+            let client = &self.clients[name];
+            if client.secret.is_empty() {
+                return Err(ConfigError::EmptySecret(name.clone()));
+            }
+            if client.projects.is_empty() {
+                return Err(ConfigError::NoProjects(name.clone()));
+            }
+            if client.permissions.is_empty() {
+                return Err(ConfigError::NoPermissions(name.clone()));
+            }
+            // a template may resolve to anything, it can't claim a project
+            for project in client
+                .projects
+                .iter()
+                .filter(|p| !p.contains(REPO_PLACEHOLDER))
+            {
+                if let Some(other) = projects.insert(project, name) {
+                    return Err(ConfigError::DuplicateProject {
+                        project: project.clone(),
+                        clients: (other.to_string(), name.clone()),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Client-level permissions replace the project defaults entirely rather
+    /// than being merged with them. A client for several projects without its
+    /// own gets only the defaults its projects have in common.
+    fn resolve_permissions(&mut self) {
+        for client in self.clients.values_mut() {
+            client.permissions = client
+                .declared_permissions
+                .clone()
+                .or_else(|| {
+                    let mut defaults = client.projects.iter().map(|project| {
+                        self.projects
+                            .get(project)
+                            .map(|project| project.permissions.clone())
+                            .unwrap_or_default()
+                    });
+                    let first = defaults.next()?;
+                    Some(defaults.fold(first, |common, next| &common & &next))
+                })
+                .unwrap_or_default();
+        }
+    }
+
+    /// The config as the binaries see it, with defaults filled in, client
+    /// permissions resolved and secrets redacted.
+    pub fn to_effective_toml(&self) -> String {
+        let value = toml::Value::try_from(self).expect("config is representable as toml");
+        toml::to_string_pretty(&value).expect("config is representable as toml")
+    }
+
+    /// Whether any client is for the project or it has settings of its own.
+    pub fn has_project(&self, project: &str) -> bool {
+        self.projects.contains_key(project)
+            || self
+                .clients
+                .values()
+                .any(|client| client.has_project(project))
+    }
+
+    pub fn project_display_name<'a>(&'a self, project: &'a str) -> &'a str {
+        self.clients
+            .values()
+            .filter(|client| client.has_project(project))
+            .find_map(|client| client.display_name.as_deref())
+            .unwrap_or(project)
+    }
+}
+
+/// A bare address is taken as a range holding only that address.
+mod cidrs {
+    use ipnet::IpNet;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(nets: &[IpNet], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(nets.iter().map(|net| net.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<IpNet>, D::Error> {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .or_else(|_| net.parse::<std::net::IpAddr>().map(IpNet::from))
+                    .map_err(|_| D::Error::custom(format!("invalid address or cidr: {net}")))
+            })
+            .collect()
+    }
+}
+
+/// Permissions are either a list of actions or `"all"`, which expands to
+/// every action there is.
+mod permissions {
+    use std::collections::HashSet;
+
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    use crate::Action;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Permissions {
+        Shorthand(String),
+        List(Vec<String>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<HashSet<Action>, D::Error> {
+        let actions = match Permissions::deserialize(d)? {
+            Permissions::Shorthand(all) if all == "all" => return Ok(Action::ALL.into()),
+            Permissions::Shorthand(other) => {
+                return Err(D::Error::custom(format!(
+                    "invalid permissions `{other}`, expected \"all\" or a list of actions"
+                )))
+            }
+            Permissions::List(actions) => actions,
+        };
+
+        actions
+            .iter()
+            .map(|action| {
+                action.parse().map_err(|_| {
+                    let known: Vec<String> = Action::ALL.iter().map(Action::to_string).collect();
+                    D::Error::custom(format!(
+                        "unknown action `{action}` in permissions, expected one of {}",
+                        known.join(", ")
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    pub fn deserialize_some<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<HashSet<Action>>, D::Error> {
+        deserialize(d).map(Some)
+    }
+}
+
+fn redacted<S: serde::Serializer, T: ?Sized>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+fn sorted<S, T>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    T: Ord + Serialize,
+{
+    let mut items: Vec<_> = set.iter().collect();
+    items.sort();
+    serializer.collect_seq(items)
+}
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     Deploy,
+    Rollback,
+    Restart,
 }
 
-#[derive(Debug, Clone)]
+impl Action {
+    pub const ALL: [Action; 3] = [Action::Deploy, Action::Rollback, Action::Restart];
+
+    /// Rollbacks jump ahead of everything else, a restart ahead of deploys
+    /// that may be stacked up behind a broken release.
+    pub fn default_priority(&self) -> u8 {
+        match self {
+            Action::Deploy => 0,
+            Action::Restart => 1,
+            Action::Rollback => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Command {
     pub action: Action,
     pub project: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default_priority")]
+    pub priority: u8,
+    /// W3C trace context of the span that dispatched the command, if any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trace_context: BTreeMap<String, String>,
+    /// The raw request body, base64 encoded on the wire.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "base64_payload"
+    )]
+    pub payload: Option<Vec<u8>>,
+    /// The sender's id for the delivery, e.g. GitHub's `X-GitHub-Delivery`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_id: Option<String>,
+    /// The server's `X-Webhook-Request-Id` for the request, logged by dispatch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The key of the client that sent the request, None for a manual
+    /// trigger. Logged by dispatch so a shared project's runs can be told
+    /// apart. Only a `{repo}` template can share a project, two clients
+    /// naming the same one outright fail validation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Taken from the payload when it is a push, so scripts need not parse it.
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pusher: Option<String>,
+    /// The sender waits on the same connection for a `CommandResult`, only
+    /// possible over the unix socket transport.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub reply: bool,
+    /// Set when the command was recorded in the journal, dispatch removes it
+    /// from there by this id once it has run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal_id: Option<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// How a command the sender waited on finished, sent back as one JSON line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResult {
+    /// None when the script could not be run or was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// The end of what the script wrote to stderr.
+    #[serde(default)]
+    pub stderr: String,
+    /// The delivery was already taken, so nothing ran for this command.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub duplicate: bool,
+}
+
+impl CommandResult {
+    pub fn to_wire(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_wire(line: &str) -> Result<Self, CommandParseError> {
+        serde_json::from_str(line).map_err(|_| CommandParseError)
+    }
+}
+
+/// Bumped when a change to `Command` would be misread by an older dispatch.
+pub const WIRE_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct WireCommand<'a> {
+    version: u32,
+    #[serde(flatten)]
+    command: &'a Command,
+}
+
+#[derive(Deserialize)]
+struct ReceivedCommand {
+    /// Absent from lines written before the version was added.
+    #[serde(default)]
+    version: u32,
+    #[serde(flatten)]
+    command: Command,
+}
+
+mod base64_payload {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match payload {
+            Some(payload) => s.serialize_str(&base64::encode(payload)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|encoded| base64::decode(encoded).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+impl Command {
+    /// One JSON object per line, the older `action project` lines are still
+    /// accepted by `from_wire` for now.
+    pub fn to_wire(&self) -> String {
+        let wire = WireCommand {
+            version: WIRE_VERSION,
+            command: self,
+        };
+        serde_json::to_string(&wire).unwrap()
+    }
+
+    pub fn from_wire(line: &str) -> Result<Self, CommandParseError> {
+        match serde_json::from_str::<ReceivedCommand>(line) {
+            Ok(received) if received.version <= WIRE_VERSION => Ok(received.command),
+            Ok(_) => Err(CommandParseError),
+            Err(_) => line.parse(),
+        }
+    }
+}
+
+fn is_default_priority(priority: &u8) -> bool {
+    *priority == 0
 }
 
+#[derive(Debug)]
 pub struct CommandParseError;
 
+/// Project names end at the first whitespace in the text form of a command,
+/// and a newline would end the line it travels in.
+pub fn is_valid_project_name(project: &str) -> bool {
+    !project.is_empty() && !project.chars().any(char::is_whitespace)
+}
+
+/// The inverse of `Display` for a command with only an action, project and
+/// environment, `deploy site` or `deploy site (staging)`.
 impl std::str::FromStr for Command {
     type Err = CommandParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (action, project) = s.split_once(" ").ok_or(CommandParseError)?;
-
-        let action = match action {
-            "deploy" => Action::Deploy,
-            _ => return Err(CommandParseError),
+        let (action, rest) = s.split_once(' ').ok_or(CommandParseError)?;
+        let (project, environment) = match rest.split_once(' ') {
+            Some((project, environment)) => {
+                let environment = environment
+                    .strip_prefix('(')
+                    .and_then(|env| env.strip_suffix(')'))
+                    .filter(|env| !env.is_empty() && !env.contains('\n'))
+                    .ok_or(CommandParseError)?;
+                (project, Some(environment.to_string()))
+            }
+            None => (rest, None),
         };
 
+        let mut command = Command::new(action.parse().map_err(|_| CommandParseError)?, project)?;
+        command.environment = environment;
+        Ok(command)
+    }
+}
+
+impl Command {
+    /// A command with only its action and project set, the project must be a
+    /// valid project name.
+    pub fn new(action: Action, project: &str) -> Result<Self, CommandParseError> {
+        if !is_valid_project_name(project) {
+            return Err(CommandParseError);
+        }
+
         Ok(Command {
             action,
             project: project.to_string(),
+            headers: BTreeMap::new(),
+            environment: None,
+            priority: action.default_priority(),
+            trace_context: BTreeMap::new(),
+            payload: None,
+            delivery_id: None,
+            request_id: None,
+            client: None,
+            git_ref: None,
+            sha: None,
+            pusher: None,
+            reply: false,
+            journal_id: None,
         })
     }
 }
 
+#[derive(Debug)]
+pub struct ActionParseError;
+
+impl std::str::FromStr for Action {
+    type Err = ActionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deploy" => Ok(Action::Deploy),
+            "rollback" => Ok(Action::Rollback),
+            "restart" => Ok(Action::Restart),
+            _ => Err(ActionParseError),
+        }
+    }
+}
+
 impl std::fmt::Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let action = match self {
             Action::Deploy => "deploy",
+            Action::Rollback => "rollback",
+            Action::Restart => "restart",
         };
         write!(f, "{}", action)
     }
@@ -75,6 +1136,263 @@ impl std::fmt::Display for Action {
 
 impl std::fmt::Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}", self.action, self.project)
+        write!(f, "{} {}", self.action, self.project)?;
+        if let Some(environment) = self.environment.as_ref() {
+            write!(f, " ({})", environment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+version = 1
+
+[webhooks]
+pipe = "/run/webhooks/pipe"
+listen_addr = "127.0.0.1"
+listen_port = 8080
+
+[dispatch]
+pipe = "/run/webhooks/pipe"
+scripts_dir = "/srv/scripts"
+
+[clients.abc]
+secret = "s3cret"
+project = "proj"
+permissions = ["deploy"]
+"#;
+
+    fn validated(toml: &str) -> Result<(), ConfigError> {
+        Config::parse(toml, ConfigFormat::Toml).unwrap().validate()
+    }
+
+    #[test]
+    fn valid_config_passes() {
+        validated(CONFIG).unwrap();
+    }
+
+    #[test]
+    fn empty_secret_is_rejected() {
+        let toml = CONFIG.replace("secret = \"s3cret\"", "secret = \"\"");
+        assert!(matches!(validated(&toml), Err(ConfigError::EmptySecret(name)) if name == "abc"));
+    }
+
+    #[test]
+    fn project_claimed_by_two_clients_is_rejected() {
+        let toml = format!(
+            "{CONFIG}\n[clients.xyz]\nsecret = \"other\"\nproject = \"proj\"\npermissions = [\"deploy\"]\n"
+        );
+        assert!(matches!(
+            validated(&toml),
+            Err(ConfigError::DuplicateProject { project, clients })
+                if project == "proj" && clients == ("abc".to_string(), "xyz".to_string())
+        ));
+    }
+
+    #[test]
+    fn template_client_may_share_a_project() {
+        let toml = format!(
+            "{CONFIG}\n[clients.xyz]\nsecret = \"other\"\nproject = \"{{repo}}\"\npermissions = [\"deploy\"]\n"
+        );
+        validated(&toml).unwrap();
+    }
+
+    #[test]
+    fn client_without_permissions_is_rejected() {
+        let toml = CONFIG.replace("permissions = [\"deploy\"]", "permissions = []");
+        assert!(matches!(validated(&toml), Err(ConfigError::NoPermissions(name)) if name == "abc"));
+    }
+
+    #[test]
+    fn every_action_parses_back_from_its_display_form() {
+        for action in Action::ALL {
+            assert_eq!(action.to_string().parse::<Action>().unwrap(), action);
+            assert_eq!(
+                serde_json::to_string(&action).unwrap(),
+                format!("\"{action}\"")
+            );
+        }
+        assert!("notify".parse::<Action>().is_err());
+        assert!("Deploy".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn wire_command_round_trips_spaces_and_payload() {
+        let mut command = Command::new(Action::Rollback, "proj").unwrap();
+        command.project = "my project".to_string();
+        command.environment = Some("staging eu".to_string());
+        command.payload = Some(b"{\"a\": 1}\n\xff\x00 tail".to_vec());
+        command
+            .headers
+            .insert("x-github-event".to_string(), "push".to_string());
+        command.delivery_id = Some("d 1".to_string());
+
+        let line = command.to_wire();
+        assert!(!line.contains('\n'));
+        let received = Command::from_wire(&line).unwrap();
+        assert_eq!(received.project, "my project");
+        assert_eq!(received.environment.as_deref(), Some("staging eu"));
+        assert_eq!(received.payload, command.payload);
+        assert_eq!(received.to_wire(), line);
+    }
+
+    #[test]
+    fn text_command_is_still_read() {
+        let received = Command::from_wire("deploy proj (staging)").unwrap();
+        assert_eq!(received.action, Action::Deploy);
+        assert_eq!(received.project, "proj");
+        assert_eq!(received.environment.as_deref(), Some("staging"));
+    }
+
+    #[test]
+    fn ipv6_listen_addr_gives_an_ipv6_socket_addr() {
+        let toml = CONFIG.replace("listen_addr = \"127.0.0.1\"", "listen_addr = \"::1\"");
+        let config = Config::parse(&toml, ConfigFormat::Toml).unwrap();
+
+        let addr =
+            std::net::SocketAddr::from((config.webhooks.listen_addr, config.webhooks.listen_port));
+        assert_eq!(addr.to_string(), "[::1]:8080");
+    }
+
+    #[test]
+    fn toml_json_and_yaml_load_the_same_config() {
+        let json = r#"{
+            "version": 1,
+            "webhooks": {
+                "pipe": "/run/webhooks/pipe",
+                "listen_addr": "127.0.0.1",
+                "listen_port": 8080
+            },
+            "dispatch": {"pipe": "/run/webhooks/pipe", "scripts_dir": "/srv/scripts"},
+            "clients": {
+                "abc": {"secret": "s3cret", "project": "proj", "permissions": ["deploy"]}
+            }
+        }"#;
+        let yaml = r#"
+version: 1
+webhooks:
+  pipe: /run/webhooks/pipe
+  listen_addr: 127.0.0.1
+  listen_port: 8080
+dispatch:
+  pipe: /run/webhooks/pipe
+  scripts_dir: /srv/scripts
+clients:
+  abc:
+    secret: s3cret
+    project: proj
+    permissions: [deploy]
+"#;
+
+        let dir = tempfile::tempdir().unwrap();
+        let load = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            let config = Config::load(&path).unwrap();
+            serde_json::to_value(&config).unwrap()
+        };
+        let toml = load("config.toml", CONFIG);
+        assert_eq!(load("config.json", json), toml);
+        assert_eq!(load("config.yaml", yaml), toml);
+        assert_eq!(load("config.yml", yaml), toml);
+    }
+
+    fn permissions(permissions: &str) -> Result<HashSet<Action>, ConfigError> {
+        let toml = CONFIG.replace(
+            "permissions = [\"deploy\"]",
+            &format!("permissions = {permissions}"),
+        );
+        Config::parse(&toml, ConfigFormat::Toml)
+            .map(|config| config.clients["abc"].permissions.clone())
+    }
+
+    #[test]
+    fn explicit_permissions_are_kept() {
+        assert_eq!(
+            permissions(r#"["deploy", "rollback"]"#).unwrap(),
+            HashSet::from([Action::Deploy, Action::Rollback])
+        );
+    }
+
+    #[test]
+    fn all_permissions_expand_to_every_action() {
+        assert_eq!(permissions(r#""all""#).unwrap(), HashSet::from(Action::ALL));
+    }
+
+    #[test]
+    fn unknown_permission_names_the_action() {
+        let err = permissions(r#"["deploy", "explode"]"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown action `explode`"), "{err}");
+        assert!(err.contains("deploy, "), "{err}");
+
+        let err = permissions(r#""everything""#).unwrap_err().to_string();
+        assert!(err.contains("invalid permissions `everything`"), "{err}");
+    }
+
+    /// Strings drawn from `alphabet`, the same ones on every run.
+    fn generated(alphabet: &[char], count: usize) -> Vec<String> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        (0..count)
+            .map(|_| {
+                let len = next() % 12;
+                (0..len)
+                    .map(|_| alphabet[next() % alphabet.len()])
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn any_valid_command_round_trips_through_display() {
+        let alphabet: Vec<char> = "aZ9-_.()/é\u{1f680} \t\n".chars().collect();
+        let names = generated(&alphabet, 500);
+        let environments = generated(&alphabet, 500);
+
+        for ((project, environment), action) in names
+            .iter()
+            .zip(environments.iter())
+            .zip(Action::ALL.iter().cycle())
+        {
+            let mut command = match Command::new(*action, project) {
+                Ok(command) => command,
+                Err(_) => {
+                    assert!(!is_valid_project_name(project), "{project:?} was rejected");
+                    continue;
+                }
+            };
+            assert!(!project.chars().any(char::is_whitespace), "{project:?}");
+            if !environment.is_empty() && !environment.contains('\n') {
+                command.environment = Some(environment.clone());
+            }
+
+            let parsed: Command = command.to_string().parse().unwrap();
+            assert_eq!(parsed.action, command.action);
+            assert_eq!(parsed.project, command.project);
+            assert_eq!(parsed.environment, command.environment);
+        }
+    }
+
+    #[test]
+    fn project_with_whitespace_is_rejected() {
+        for project in ["", "my project", "proj\n", "\tproj"] {
+            assert!(
+                Command::new(Action::Deploy, project).is_err(),
+                "{project:?}"
+            );
+        }
+        let toml = CONFIG.replace("project = \"proj\"", "project = \"my proj\"");
+        assert!(validated(&toml).is_err());
     }
 }
@@ -0,0 +1,198 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing_appender::rolling::{Builder, Rotation};
+use tracing_subscriber::fmt::{
+    self,
+    format::{DefaultFields, Format},
+    writer::BoxMakeWriter,
+};
+use tracing_subscriber::{reload, Registry};
+
+use crate::{LogConfig, LogRotation};
+
+pub type FileLayer = fmt::Layer<Registry, DefaultFields, Format, BoxMakeWriter>;
+pub type FileLogHandle = reload::Handle<Option<FileLayer>, Registry>;
+
+/// Goes first on the registry, logging is set up before the config that
+/// says where the file is has been read. Writes nothing until
+/// `set_file_log` is called.
+pub fn file_layer() -> (reload::Layer<Option<FileLayer>, Registry>, FileLogHandle) {
+    reload::Layer::new(None)
+}
+
+/// Starts logging to the configured file, if there is one.
+pub fn set_file_log(handle: &FileLogHandle, config: &LogConfig) -> io::Result<()> {
+    let writer = match make_writer(config)? {
+        Some(writer) => writer,
+        None => return Ok(()),
+    };
+
+    let layer = fmt::layer().with_ansi(false).with_writer(writer);
+    handle.reload(Some(layer)).map_err(io::Error::other)
+}
+
+pub fn make_writer(config: &LogConfig) -> io::Result<Option<BoxMakeWriter>> {
+    let path = match config.log_file.as_deref() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let writer = match config.log_rotation {
+        LogRotation::Never => BoxMakeWriter::new(Mutex::new(append(path)?)),
+        LogRotation::Daily => {
+            let (dir, name) = split(path)?;
+            let appender = Builder::new()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(name)
+                .max_log_files(config.log_keep_files + 1)
+                .build(dir)
+                .map_err(io::Error::other)?;
+            BoxMakeWriter::new(appender)
+        }
+        LogRotation::Size => BoxMakeWriter::new(Mutex::new(SizeRotating::open(
+            path,
+            config.log_max_bytes,
+            config.log_keep_files,
+        )?)),
+    };
+    Ok(Some(writer))
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn split(path: &Path) -> io::Result<(&Path, &str)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "log_file has no file name"))?;
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    Ok((dir, name))
+}
+
+/// Rotates before a write would take the file past `max_bytes`, so each
+/// event lands whole in one file.
+struct SizeRotating {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl SizeRotating {
+    fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = append(path)?;
+        let written = file.metadata()?.len();
+        Ok(SizeRotating {
+            path: path.to_path_buf(),
+            file,
+            written,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn rotated(&self, idx: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{idx}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for idx in (1..self.keep).rev() {
+            let from = self.rotated(idx);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(idx + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, self.rotated(1))?;
+            self.file = append(&self.path)?;
+        } else {
+            self.file.set_len(0)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotating {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use super::*;
+
+    fn log_config(log_file: Option<PathBuf>, log_rotation: LogRotation) -> LogConfig {
+        LogConfig {
+            log_file,
+            log_rotation,
+            log_max_bytes: 16,
+            log_keep_files: 2,
+        }
+    }
+
+    #[test]
+    fn no_log_file_means_no_writer() {
+        let config = log_config(None, LogRotation::Never);
+        assert!(make_writer(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn writer_appends_to_the_configured_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks.log");
+        std::fs::write(&path, "earlier\n").unwrap();
+
+        let config = log_config(Some(path.clone()), LogRotation::Never);
+        let writer = make_writer(&config).unwrap().unwrap();
+        writer.make_writer().write_all(b"logged\n").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "earlier\nlogged\n");
+    }
+
+    #[test]
+    fn size_rotation_keeps_the_configured_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks.log");
+
+        let config = log_config(Some(path.clone()), LogRotation::Size);
+        let writer = make_writer(&config).unwrap().unwrap();
+        for line in [
+            "first line\n",
+            "second line\n",
+            "third line\n",
+            "fourth line\n",
+        ] {
+            writer.make_writer().write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("webhooks.log"), "fourth line\n");
+        assert_eq!(read("webhooks.log.1"), "third line\n");
+        assert_eq!(read("webhooks.log.2"), "second line\n");
+        assert!(!dir.path().join("webhooks.log.3").exists());
+    }
+}
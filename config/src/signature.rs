@@ -0,0 +1,205 @@
+use std::fmt::Write;
+
+use subtle::ConstantTimeEq;
+
+use crate::SignatureAlgorithm;
+
+pub fn expected_signature(algorithm: SignatureAlgorithm, secret: &str, body: &[u8]) -> String {
+    encode(prefix(algorithm), &digest(algorithm, secret, body))
+}
+
+/// Compares the raw digests in constant time, so response timing does not
+/// reveal how much of a forged signature was correct.
+pub fn matches(algorithm: SignatureAlgorithm, secret: &str, body: &[u8], signature: &str) -> bool {
+    if algorithm == SignatureAlgorithm::GitlabToken {
+        return token_matches(secret, signature);
+    }
+
+    let provided = match signature.strip_prefix(prefix(algorithm)).and_then(decode) {
+        Some(provided) => provided,
+        None => return false,
+    };
+    let expected = digest(algorithm, secret, body);
+    provided.ct_eq(&expected).into()
+}
+
+/// Compares a token sent as is against the secret in constant time.
+pub fn token_matches(secret: &str, token: &str) -> bool {
+    token.as_bytes().ct_eq(secret.as_bytes()).into()
+}
+
+/// Checks a signature made with any supported algorithm, which is taken from
+/// the signature's prefix.
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let algorithm = match signature.split_once('=') {
+        Some(("sha1", _)) => SignatureAlgorithm::Sha1,
+        Some(("sha256", _)) => SignatureAlgorithm::Sha256,
+        Some(("sha512", _)) => SignatureAlgorithm::Sha512,
+        Some(("blake3", _)) => SignatureAlgorithm::Blake3,
+        _ => return false,
+    };
+    matches(algorithm, secret, body, signature)
+}
+
+fn prefix(algorithm: SignatureAlgorithm) -> &'static str {
+    match algorithm {
+        SignatureAlgorithm::Sha1 => "sha1=",
+        SignatureAlgorithm::Sha256 => "sha256=",
+        SignatureAlgorithm::Sha512 => "sha512=",
+        SignatureAlgorithm::Blake3 => "blake3=",
+        SignatureAlgorithm::GitlabToken => "",
+    }
+}
+
+fn digest(algorithm: SignatureAlgorithm, secret: &str, body: &[u8]) -> Vec<u8> {
+    match algorithm {
+        SignatureAlgorithm::Sha1 => hmac_sha1_compact::HMAC::mac(body, secret.as_bytes()).to_vec(),
+        SignatureAlgorithm::Sha256 => hmac_sha256::HMAC::mac(body, secret.as_bytes()).to_vec(),
+        SignatureAlgorithm::Sha512 => hmac_sha512::HMAC::mac(body, secret.as_bytes()).to_vec(),
+        SignatureAlgorithm::Blake3 => {
+            // blake3's keyed mode takes exactly 32 bytes, so stretch the secret into a key first
+            let key = blake3::hash(secret.as_bytes());
+            blake3::keyed_hash(key.as_bytes(), body).as_bytes().to_vec()
+        }
+        // the token is sent as is, there is nothing of the body to digest
+        SignatureAlgorithm::GitlabToken => secret.as_bytes().to_vec(),
+    }
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode(prefix: &str, digest: &[u8]) -> String {
+    digest.iter().fold(String::from(prefix), |mut acc, n| {
+        let _ = write!(acc, "{:02x}", n);
+        acc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"{\"ref\": \"refs/heads/main\"}";
+
+    #[test]
+    fn expected_signature_matches() {
+        let signature = expected_signature(SignatureAlgorithm::Sha256, "s3cret", BODY);
+        assert!(matches(
+            SignatureAlgorithm::Sha256,
+            "s3cret",
+            BODY,
+            &signature
+        ));
+        assert!(verify("s3cret", BODY, &signature));
+    }
+
+    #[test]
+    fn digest_differing_in_its_last_byte_is_rejected() {
+        let mut digest = digest(SignatureAlgorithm::Sha256, "s3cret", BODY);
+        *digest.last_mut().unwrap() ^= 1;
+        let signature = encode("sha256=", &digest);
+
+        assert!(!matches(
+            SignatureAlgorithm::Sha256,
+            "s3cret",
+            BODY,
+            &signature
+        ));
+    }
+
+    #[test]
+    fn truncated_signature_is_rejected() {
+        let signature = expected_signature(SignatureAlgorithm::Sha256, "s3cret", BODY);
+        let truncated = &signature[..signature.len() - 2];
+
+        assert!(!matches(
+            SignatureAlgorithm::Sha256,
+            "s3cret",
+            BODY,
+            truncated
+        ));
+    }
+
+    #[test]
+    fn each_algorithm_verifies_its_own_signature() {
+        for algorithm in [
+            SignatureAlgorithm::Sha256,
+            SignatureAlgorithm::Sha512,
+            SignatureAlgorithm::Blake3,
+        ] {
+            let signature = expected_signature(algorithm, "s3cret", BODY);
+            assert!(signature.starts_with(prefix(algorithm)), "{signature}");
+            assert!(
+                matches(algorithm, "s3cret", BODY, &signature),
+                "{algorithm:?}"
+            );
+            assert!(verify("s3cret", BODY, &signature), "{algorithm:?}");
+            assert!(
+                !matches(algorithm, "other", BODY, &signature),
+                "{algorithm:?}"
+            );
+            assert!(
+                !matches(algorithm, "s3cret", b"{}", &signature),
+                "{algorithm:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn signature_for_another_algorithm_is_rejected() {
+        let sha512 = expected_signature(SignatureAlgorithm::Sha512, "s3cret", BODY);
+        assert!(!matches(
+            SignatureAlgorithm::Sha256,
+            "s3cret",
+            BODY,
+            &sha512
+        ));
+
+        let unprefixed = sha512.trim_start_matches("sha512=");
+        assert!(!matches(
+            SignatureAlgorithm::Sha512,
+            "s3cret",
+            BODY,
+            unprefixed
+        ));
+        assert!(!verify("s3cret", BODY, unprefixed));
+    }
+
+    /// RFC 2202 and RFC 4231 test case 2.
+    #[test]
+    fn hmac_test_vectors() {
+        let body = b"what do ya want for nothing?";
+        let vectors = [
+            (
+                SignatureAlgorithm::Sha1,
+                "sha1=effcdf6ae5eb2fa2d27416d5f184df9c259a7c79",
+            ),
+            (
+                SignatureAlgorithm::Sha256,
+                "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                SignatureAlgorithm::Sha512,
+                "sha512=164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7\
+                 ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737",
+            ),
+        ];
+        for (algorithm, signature) in vectors {
+            assert_eq!(expected_signature(algorithm, "Jefe", body), signature);
+            assert!(matches(algorithm, "Jefe", body, signature));
+        }
+    }
+
+    #[test]
+    fn gitlab_token_is_compared_as_is() {
+        let algorithm = SignatureAlgorithm::GitlabToken;
+        assert!(matches(algorithm, "s3cret", BODY, "s3cret"));
+        assert!(!matches(algorithm, "s3cret", BODY, "s3cre"));
+        assert!(!matches(algorithm, "s3cret", BODY, "S3CRET"));
+    }
+}
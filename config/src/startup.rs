@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+use crate::{Config, ConfigError, ConfigFormat};
+
+/// A failure that stops either binary from starting. Each class exits with
+/// its own code so supervisors can tell a retryable failure from one that
+/// needs a human:
+///
+/// | code | meaning                                            |
+/// |------|----------------------------------------------------|
+/// | 1    | any other startup failure                          |
+/// | 65   | the config file is not valid toml or has bad types |
+/// | 66   | the config file could not be read                  |
+/// | 69   | the server could not bind its listen address       |
+/// | 74   | the dispatch pipe could not be opened              |
+/// | 78   | the config parsed but is unusable                  |
+///
+/// The codes follow `sysexits.h`, clap already uses 2 for bad arguments.
+#[derive(Debug)]
+pub enum StartupError {
+    ConfigNotFound(PathBuf, std::io::Error),
+    ConfigParse(ConfigError),
+    ConfigValidation(String),
+    Bind(String),
+    Pipe(PathBuf, std::io::Error),
+    Other(String),
+}
+
+impl StartupError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Other(_) => 1,
+            StartupError::ConfigParse(_) => 65,
+            StartupError::ConfigNotFound(..) => 66,
+            StartupError::Bind(_) => 69,
+            StartupError::Pipe(..) => 74,
+            StartupError::ConfigValidation(_) => 78,
+        }
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+impl std::fmt::Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupError::ConfigNotFound(path, err) => {
+                write!(f, "unable to read config {}: {}", path.display(), err)
+            }
+            StartupError::ConfigParse(err) => write!(f, "{}", err),
+            StartupError::ConfigValidation(err) => write!(f, "invalid config: {}", err),
+            StartupError::Bind(err) => write!(f, "{}", err),
+            StartupError::Pipe(path, err) => {
+                write!(f, "unable to open pipe {}: {}", path.display(), err)
+            }
+            StartupError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<ConfigError> for StartupError {
+    fn from(err: ConfigError) -> Self {
+        match err {
+            ConfigError::Parse(_) | ConfigError::ParseJson(_) | ConfigError::ParseYaml(_) => {
+                StartupError::ConfigParse(err)
+            }
+            ConfigError::MissingVersion
+            | ConfigError::UnsupportedVersion(_)
+            | ConfigError::EmptySecret(_)
+            | ConfigError::DuplicateProject { .. }
+            | ConfigError::NoPermissions(_)
+            | ConfigError::NoProjects(_)
+            | ConfigError::InvalidProjectName(_)
+            | ConfigError::NoScriptsDir
+            | ConfigError::EmptyAdminSecret
+            | ConfigError::UnsetEnvVar(_)
+            | ConfigError::UnterminatedEnvVar => StartupError::ConfigValidation(err.to_string()),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, StartupError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| StartupError::ConfigNotFound(path.to_path_buf(), err))?;
+        let config = Config::parse(&contents, ConfigFormat::from_path(path))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Collapses runs of identical messages, logging the first and then a
+/// `(repeated N times)` summary at most once per interval until a different
+/// message breaks the run.
+pub struct ThrottledLog {
+    interval: Duration,
+    last: Mutex<Option<Repeat>>,
+}
+
+struct Repeat {
+    hash: u64,
+    message: String,
+    level: tracing::Level,
+    repeated: u64,
+    last_logged: Instant,
+}
+
+impl ThrottledLog {
+    pub const fn new(interval: Duration) -> Self {
+        ThrottledLog {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn error(&self, message: impl std::fmt::Display) {
+        self.log(tracing::Level::ERROR, message.to_string());
+    }
+
+    pub fn warn(&self, message: impl std::fmt::Display) {
+        self.log(tracing::Level::WARN, message.to_string());
+    }
+
+    /// Logs the summary for any repeats not yet reported.
+    pub fn flush(&self) {
+        if let Some(repeat) = self.last.lock().unwrap().as_mut() {
+            repeat.flush(Instant::now());
+        }
+    }
+
+    fn log(&self, level: tracing::Level, message: String) {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        let hash = hasher.finish();
+        let now = Instant::now();
+
+        let mut last = self.last.lock().unwrap();
+        if let Some(repeat) = last.as_mut().filter(|r| r.hash == hash && r.level == level) {
+            repeat.repeated += 1;
+            if now.duration_since(repeat.last_logged) >= self.interval {
+                repeat.flush(now);
+            }
+            return;
+        }
+
+        if let Some(mut previous) = last.take() {
+            previous.flush(now);
+        }
+
+        emit(level, &message);
+        *last = Some(Repeat {
+            hash,
+            message,
+            level,
+            repeated: 0,
+            last_logged: now,
+        });
+    }
+}
+
+impl Repeat {
+    fn flush(&mut self, now: Instant) {
+        if self.repeated > 0 {
+            emit(
+                self.level,
+                &format!("{} (repeated {} times)", self.message, self.repeated),
+            );
+            self.repeated = 0;
+        }
+        self.last_logged = now;
+    }
+}
+
+fn emit(level: tracing::Level, message: &str) {
+    match level {
+        tracing::Level::ERROR => tracing::error!("{message}"),
+        tracing::Level::WARN => tracing::warn!("{message}"),
+        tracing::Level::INFO => tracing::info!("{message}"),
+        tracing::Level::DEBUG => tracing::debug!("{message}"),
+        tracing::Level::TRACE => tracing::trace!("{message}"),
+    }
+}
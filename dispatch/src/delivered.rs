@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Delivery ids dispatch has taken commands for, so a webhook redelivered
+/// past the server's replay check still only runs once. With a path the ids
+/// are kept there as `<secs> <id>` lines and outlive a restart.
+pub struct Delivered {
+    window: Duration,
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    seen: HashMap<String, u64>,
+    /// Lines in the file, rewritten without the expired ones once most are.
+    lines: usize,
+}
+
+impl Delivered {
+    /// A zero window remembers nothing.
+    pub fn load(path: Option<PathBuf>, window: Duration) -> Self {
+        let delivered = Delivered {
+            window,
+            path,
+            state: Mutex::default(),
+        };
+        if window.is_zero() {
+            return delivered;
+        }
+
+        if let Some(path) = delivered.path.as_ref() {
+            match File::open(path) {
+                Ok(file) => {
+                    let mut state = delivered.state.lock().unwrap();
+                    for line in BufReader::new(file).lines().map_while(Result::ok) {
+                        if let Some((at, id)) = line.split_once(' ') {
+                            if let Ok(at) = at.parse() {
+                                state.seen.insert(id.to_string(), at);
+                            }
+                        }
+                    }
+                    delivered.compact(&mut state);
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => tracing::error!(
+                    "unable to read delivered ids from {}: {err}",
+                    path.display()
+                ),
+            }
+        }
+        delivered
+    }
+
+    /// Records `id`, false if a command for it was already taken within the
+    /// window.
+    pub fn insert(&self, id: &str) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+
+        let now = now();
+        let mut state = self.state.lock().unwrap();
+        if state
+            .seen
+            .get(id)
+            .is_some_and(|&at| now.saturating_sub(at) < self.window.as_secs())
+        {
+            return false;
+        }
+        state.seen.insert(id.to_string(), now);
+
+        if let Some(path) = self.path.as_ref() {
+            let appended = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{now} {id}"));
+            match appended {
+                Ok(()) => state.lines += 1,
+                Err(err) => tracing::error!("unable to record delivery {id}: {err}"),
+            }
+            if state.lines > 2 * state.seen.len() + 64 {
+                self.compact(&mut state);
+            }
+        }
+        true
+    }
+
+    /// Lets a command for `id` be taken again, for one still waiting in the
+    /// journal that never got to run. The file is rewritten without it, so a
+    /// restart before it runs again doesn't find it taken.
+    pub fn forget(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.seen.remove(id).is_some() {
+            self.compact(&mut state);
+        }
+    }
+
+    /// Drops expired ids and rewrites the file with the rest.
+    fn compact(&self, state: &mut State) {
+        let now = now();
+        let window = self.window.as_secs();
+        state.seen.retain(|_, at| now.saturating_sub(*at) < window);
+
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let written = File::create(&tmp).and_then(|mut file| {
+            for (id, at) in state.seen.iter() {
+                writeln!(file, "{at} {id}")?;
+            }
+            file.sync_data()?;
+            std::fs::rename(&tmp, path)
+        });
+        match written {
+            Ok(()) => state.lines = state.seen.len(),
+            Err(err) => tracing::error!(
+                "unable to rewrite delivered ids in {}: {err}",
+                path.display()
+            ),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn delivery_is_taken_once() {
+        let delivered = Delivered::load(None, WINDOW);
+
+        assert!(delivered.insert("a"));
+        assert!(!delivered.insert("a"));
+        assert!(delivered.insert("b"));
+    }
+
+    #[test]
+    fn taken_deliveries_outlive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delivered");
+
+        assert!(Delivered::load(Some(path.clone()), WINDOW).insert("a"));
+        assert!(!Delivered::load(Some(path), WINDOW).insert("a"));
+    }
+
+    #[test]
+    fn forgotten_delivery_stays_forgotten_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delivered");
+        Delivered::load(Some(path.clone()), WINDOW).insert("a");
+
+        Delivered::load(Some(path.clone()), WINDOW).forget("a");
+
+        let restarted = Delivered::load(Some(path), WINDOW);
+        assert!(restarted.insert("a"));
+        assert!(!restarted.insert("a"));
+    }
+}
@@ -0,0 +1,122 @@
+use std::process::ExitStatus;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use config::{Action, Command};
+
+const QUEUE_SIZE: usize = 64;
+const ATTEMPTS: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventStatus {
+    Success,
+    Failure,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DispatchEvent {
+    pub project: String,
+    pub action: Action,
+    pub status: EventStatus,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub request_id: Option<String>,
+    pub timestamp: u64,
+}
+
+impl DispatchEvent {
+    pub fn new(command: &Command, status: Option<ExitStatus>, duration: Duration) -> Self {
+        let event_status = match status {
+            Some(status) if status.success() => EventStatus::Success,
+            Some(_) => EventStatus::Failure,
+            None => EventStatus::Error,
+        };
+
+        DispatchEvent {
+            project: command.project.clone(),
+            action: command.action,
+            status: event_status,
+            exit_code: status.and_then(|s| s.code()),
+            duration_ms: duration.as_millis() as u64,
+            request_id: command.request_id.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Posts events to the aggregator from a background thread so a slow or
+/// unreachable aggregator never holds up script execution.
+pub struct EventSink {
+    tx: SyncSender<DispatchEvent>,
+}
+
+impl EventSink {
+    pub fn new(url: String) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<DispatchEvent>(QUEUE_SIZE);
+
+        std::thread::spawn(move || {
+            let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+            for event in rx {
+                post(&agent, &url, &event);
+            }
+        });
+
+        EventSink { tx }
+    }
+
+    pub fn send(&self, event: DispatchEvent) {
+        match self.tx.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(event)) => {
+                tracing::warn!("event sink queue full, dropping event: {event:?}")
+            }
+            Err(TrySendError::Disconnected(_)) => tracing::error!("event sink worker has stopped"),
+        }
+    }
+}
+
+fn post(agent: &ureq::Agent, url: &str, event: &DispatchEvent) {
+    let mut backoff = BASE_BACKOFF;
+    for attempt in 1..=ATTEMPTS {
+        match agent.post(url).send_json(event) {
+            Ok(_) => return,
+            Err(err) => {
+                tracing::warn!("unable to send event (attempt {attempt}/{ATTEMPTS}): {err}");
+            }
+        }
+
+        if attempt < ATTEMPTS {
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    tracing::error!("giving up on event for {} {}", event.action, event.project);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn event_carries_the_request_id() {
+        let mut command = Command::new(Action::Deploy, "proj").unwrap();
+        command.request_id = Some("req-1".to_string());
+
+        let status = ExitStatus::from_raw(0);
+        let event = DispatchEvent::new(&command, Some(status), Duration::from_millis(5));
+        assert_eq!(event.request_id.as_deref(), Some("req-1"));
+        assert!(matches!(event.status, EventStatus::Success));
+    }
+}
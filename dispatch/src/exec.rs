@@ -0,0 +1,371 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+const RELAY_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct ExecOptions {
+    pub allocate_pty: bool,
+    pub timeout: Option<Duration>,
+    pub max_output_bytes: usize,
+    /// Log the command instead of running it, as if it had succeeded.
+    pub dry_run: bool,
+}
+
+pub struct Exited {
+    pub status: ExitStatus,
+    /// Everything captured from stderr, or all output when run with a pty.
+    pub errors: String,
+    /// Killed for running past the script timeout.
+    pub timed_out: bool,
+}
+
+/// `input`, if any, is written to the script's stdin, a script that exits
+/// without reading it is not treated as an error.
+pub fn run(
+    mut command: Command,
+    options: &ExecOptions,
+    label: &str,
+    input: Option<&[u8]>,
+) -> io::Result<Exited> {
+    if options.dry_run {
+        tracing::info!("dry run, not executing {label}: {command:?}");
+        return Ok(Exited {
+            status: ExitStatus::from_raw(0),
+            errors: String::new(),
+            timed_out: false,
+        });
+    }
+
+    // a fresh process group lets a timeout take down anything the script spawned too
+    command.process_group(0);
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+
+    let mut relays = Relays::new(options.max_output_bytes);
+    let mut child = if options.allocate_pty {
+        match Pty::open() {
+            Ok(pty) => spawn_pty(command, pty, label, &mut relays)?,
+            Err(err) => {
+                tracing::warn!("unable to allocate pty, falling back to pipes: {err:?}");
+                spawn_piped(command, label, &mut relays)?
+            }
+        }
+    } else {
+        spawn_piped(command, label, &mut relays)?
+    };
+
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // written from a thread so a script that never reads can't block the wait
+        let input = input.to_vec();
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    let waited = wait(child, options.timeout, label);
+    let errors = relays.finish();
+    let (status, timed_out) = waited?;
+
+    if !status.success() {
+        if errors.is_empty() {
+            tracing::error!("{label} failed with {status}");
+        } else {
+            tracing::error!("{label} failed with {status}, stderr:\n{errors}");
+        }
+    }
+
+    Ok(Exited {
+        status,
+        errors,
+        timed_out,
+    })
+}
+
+fn spawn_piped(mut command: Command, label: &str, relays: &mut Relays) -> io::Result<Child> {
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command.spawn()?;
+
+    if let Some(stdout) = child.stdout.take() {
+        relays.spawn(stdout, label, "stdout");
+    }
+    if let Some(stderr) = child.stderr.take() {
+        relays.spawn(stderr, label, "stderr");
+    }
+
+    Ok(child)
+}
+
+fn spawn_pty(
+    mut command: Command,
+    pty: Pty,
+    label: &str,
+    relays: &mut Relays,
+) -> io::Result<Child> {
+    command
+        .stdout(Stdio::from(pty.slave.try_clone()?))
+        .stderr(Stdio::from(pty.slave));
+    let child = command.spawn()?;
+
+    // the command holds the parent's copies of the pty slave, they must be closed
+    // for the master to see the end of output once the child exits
+    drop(command);
+
+    relays.spawn(pty.master, label, "pty");
+
+    Ok(child)
+}
+
+/// The exit status and whether the child was killed for timing out.
+fn wait(
+    mut child: Child,
+    timeout: Option<Duration>,
+    label: &str,
+) -> io::Result<(ExitStatus, bool)> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok((child.wait()?, false)),
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+
+        if Instant::now() >= deadline {
+            tracing::error!("{label} timed out after {timeout:?}, killing process group");
+            kill_group(&child);
+            return Ok((child.wait()?, true));
+        }
+
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+fn kill_group(child: &Child) {
+    let pgid = child.id() as libc::pid_t;
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        tracing::error!(
+            "unable to kill process group {pgid}: {:?}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Threads logging a script's output streams within the span that ran it,
+/// keeping the error output to report if the script fails.
+struct Relays {
+    count: usize,
+    max_bytes: usize,
+    errors: Arc<Mutex<String>>,
+    done_tx: mpsc::Sender<()>,
+    done_rx: mpsc::Receiver<()>,
+}
+
+impl Relays {
+    fn new(max_bytes: usize) -> Self {
+        let (done_tx, done_rx) = mpsc::channel();
+        Relays {
+            count: 0,
+            max_bytes,
+            errors: Arc::new(Mutex::new(String::new())),
+            done_tx,
+            done_rx,
+        }
+    }
+
+    fn spawn<R: Read + Send + 'static>(&mut self, reader: R, label: &str, stream: &'static str) {
+        let label = label.to_string();
+        let done = self.done_tx.clone();
+        let span = tracing::Span::current();
+        let max_bytes = self.max_bytes;
+        // with a pty both streams arrive together, so all of it counts as errors
+        let errors = (stream != "stdout").then(|| self.errors.clone());
+        self.count += 1;
+        std::thread::spawn(move || {
+            let _enter = span.enter();
+            relay(reader, &label, stream, max_bytes, errors);
+            let _ = done.send(());
+        });
+    }
+
+    /// Waits briefly for output to drain so a leftover grandchild holding a
+    /// stream open can't stall the dispatch loop, then hands back the error
+    /// output seen.
+    fn finish(self) -> String {
+        for _ in 0..self.count {
+            if self.done_rx.recv_timeout(RELAY_GRACE).is_err() {
+                break;
+            }
+        }
+        let errors = self.errors.lock().unwrap();
+        errors.clone()
+    }
+}
+
+fn relay<R: Read>(
+    reader: R,
+    label: &str,
+    stream: &str,
+    max_bytes: usize,
+    errors: Option<Arc<Mutex<String>>>,
+) {
+    let mut logged = 0;
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            // a pty master reports EIO rather than EOF once the child side has closed
+            Err(_) => break,
+        };
+
+        // keep draining past the cap so the script never blocks on a full pipe
+        if logged >= max_bytes {
+            continue;
+        }
+
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        tracing::info!("{label} {stream}: {line}");
+        if let Some(errors) = errors.as_ref() {
+            let mut errors = errors.lock().unwrap();
+            errors.push_str(line);
+            errors.push('\n');
+        }
+
+        logged += line.len() + 1;
+        if logged >= max_bytes {
+            tracing::warn!("{label} {stream}: output truncated after {max_bytes} bytes");
+        }
+    }
+}
+
+struct Pty {
+    master: File,
+    slave: OwnedFd,
+}
+
+impl Pty {
+    fn open() -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for fd in [master, slave] {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+
+        unsafe {
+            Ok(Pty {
+                master: File::from_raw_fd(master),
+                slave: OwnedFd::from_raw_fd(slave),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn options() -> ExecOptions {
+        ExecOptions {
+            allocate_pty: false,
+            timeout: None,
+            max_output_bytes: 1024,
+            dry_run: false,
+        }
+    }
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    /// Gone, or left a zombie for whoever reaps orphans here.
+    fn is_dead(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+            Ok(stat) => stat
+                .rsplit_once(')')
+                .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[test]
+    fn timeout_kills_the_scripts_children_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let options = ExecOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..options()
+        };
+        let script = format!(
+            "sh -c 'sleep 30 & echo $! > {}; wait' & wait",
+            pid_file.display()
+        );
+
+        let exited = run(sh(&script), &options, "test", None).unwrap();
+        assert!(exited.timed_out);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let pid = pid.trim();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_dead(pid) {
+            assert!(Instant::now() < deadline, "grandchild {pid} still running");
+            std::thread::sleep(WAIT_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn script_reads_the_payload_from_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let received = dir.path().join("received");
+        let payload: Vec<u8> = (0..=255).cycle().take(256 * 1024).collect();
+
+        let script = format!("cat > {}", received.display());
+        let exited = run(sh(&script), &options(), "test", Some(&payload)).unwrap();
+        assert!(exited.status.success());
+        assert_eq!(std::fs::read(received).unwrap(), payload);
+    }
+
+    #[test]
+    fn script_may_ignore_the_payload() {
+        let payload = vec![b'x'; 256 * 1024];
+        let exited = run(sh("exit 0"), &options(), "test", Some(&payload)).unwrap();
+        assert!(exited.status.success());
+    }
+
+    #[test]
+    fn script_output_is_logged_under_its_label() {
+        let logs = test_support::global_logs();
+        let script = "echo to stdout; echo to stderr >&2; exit 1";
+
+        let exited = run(sh(script), &options(), "output-test", None).unwrap();
+        assert_eq!(exited.errors, "to stderr\n");
+
+        let logs = logs.contents();
+        assert!(logs.contains("output-test stdout: to stdout"), "{logs}");
+        assert!(logs.contains("output-test stderr: to stderr"), "{logs}");
+    }
+}
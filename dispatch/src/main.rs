@@ -1,80 +1,1430 @@
+mod daemon;
+mod delivered;
+mod events;
+mod exec;
+mod notify;
+mod scheduler;
+mod scripts;
+mod signals;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod user;
+
+#[cfg(test)]
+mod test_support;
+
 use clap::Parser;
-use config::Config;
+use config::journal::Journal;
+use config::logging::FileLogHandle;
+use config::startup::StartupError;
+use config::throttle::ThrottledLog;
+use config::{Command, CommandResult, Config, ProjectConfig, Transport};
+use delivered::Delivered;
+use events::{DispatchEvent, EventSink};
+use exec::Exited;
+use notify::{FailureNotification, FailureNotifier};
+use scheduler::{Admit, CommandQueue, SchedulerPolicy};
+use scripts::ScriptCache;
+use signals::Running;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use user::RunAs;
+
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use std::collections::HashSet;
-use std::io::BufRead;
-use std::path::PathBuf;
+/// A misconfigured pipe can fail on every read, so identical errors are
+/// collapsed rather than flooding the log.
+static PIPE_ERRORS: ThrottledLog = ThrottledLog::new(Duration::from_secs(10));
+
+/// Pause before reopening the pipe, doubled up to the max each time, so a
+/// pipe failing on every read is not reopened in a spin.
+const REOPEN_DELAY: Duration = Duration::from_millis(10);
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Parser)]
 struct Args {
     #[clap(long, default_value = "config.toml")]
     config: PathBuf,
+    /// Print the config with defaults applied and secrets redacted, then exit
+    #[clap(long)]
+    print_effective_config: bool,
+    /// Detach from the terminal and run in the background
+    #[clap(long)]
+    daemonize: bool,
+    #[clap(long)]
+    pid_file: Option<PathBuf>,
+    /// Where logs are written when daemonized, defaults to discarding them
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+    /// Run the script for a project and action once, exactly as a dispatched
+    /// command would, then exit
+    #[clap(long, number_of_values = 2, value_names = &["PROJECT", "ACTION"])]
+    test_script: Option<Vec<String>>,
+    /// Resolve scripts for commands as usual but only log what would run
+    #[clap(long)]
+    dry_run: bool,
+    /// OTLP/HTTP traces endpoint to export spans to
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
 }
 
-fn main() {
-    tracing_subscriber::fmt::init();
+impl Args {
+    /// Runs that exit once their one job is done rather than serving the
+    /// pipe, and so must not leave a pid file behind.
+    fn one_shot(&self) -> bool {
+        self.test_script.is_some() || self.print_effective_config
+    }
+}
 
+fn main() {
     let args = Args::parse();
 
+    if args.daemonize {
+        if let Err(err) = daemon::daemonize(args.log_file.as_deref()) {
+            eprintln!("unable to daemonize: {err:?}");
+            std::process::exit(1);
+        }
+    }
+
+    let (file_log, file_log_handle) = config::logging::file_layer();
+    let subscriber = tracing_subscriber::registry()
+        .with(file_log)
+        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer());
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(args.otlp_endpoint.as_deref().map(|endpoint| {
+        telemetry::layer(endpoint).unwrap_or_else(|err| {
+            eprintln!("unable to configure otlp exporter: {err}");
+            std::process::exit(1);
+        })
+    }));
+    subscriber.init();
+
+    if let Err(err) = run(args, file_log_handle) {
+        tracing::error!("{err}");
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn run(args: Args, file_log: FileLogHandle) -> Result<(), StartupError> {
+    if let Some(pid_file) = args.pid_file.as_ref().filter(|_| !args.one_shot()) {
+        daemon::write_pid_file(pid_file).map_err(|err| {
+            StartupError::Other(format!(
+                "unable to write pid file {}: {err}",
+                pid_file.display()
+            ))
+        })?;
+    }
+
     tracing::info!("loading config from: {}", args.config.display());
 
-    let config_file = std::fs::read_to_string(args.config).unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
+    let config = Config::load(&args.config)?;
+    if args.print_effective_config {
+        print!("{}", config.to_effective_toml());
+        return Ok(());
+    }
+    config::logging::set_file_log(&file_log, &config.dispatch.log)
+        .map_err(|err| StartupError::Other(format!("unable to open log file: {err}")))?;
     let config: &'static Config = Box::leak(Box::new(config));
 
+    let signals = signals::install(
+        args.pid_file.clone().filter(|_| !args.one_shot()),
+        Duration::from_secs(config.dispatch.shutdown_grace_secs),
+    )
+    .map_err(|err| StartupError::Other(format!("unable to install signal handlers: {err}")))?;
+
     tracing::info!("opening pipe: {}", config.dispatch.pipe.display());
 
-    let projects: HashSet<String> = config
-        .clients
-        .values()
-        .map(|client| client.project.clone())
+    let forward_headers: HashSet<String> = config
+        .dispatch
+        .forward_headers
+        .iter()
+        .map(|name| name.to_ascii_lowercase())
         .collect();
 
+    let shadow_scripts = config
+        .projects
+        .iter()
+        .filter_map(|(name, project)| {
+            let dir = project.shadow_scripts_dir.clone()?;
+            Some((name.clone(), Mutex::new(ScriptCache::new(vec![dir]))))
+        })
+        .collect();
+
+    let run_as = RunAs::resolve(
+        config.dispatch.run_as_user.as_deref(),
+        config.dispatch.run_as_group.as_deref(),
+    )
+    .map_err(|err| {
+        StartupError::ConfigValidation(format!(
+            "unable to resolve the user to run scripts as: {err}"
+        ))
+    })?;
+
+    let journal = config.dispatch.journal.as_deref().map(Journal::new);
+    // a dry run runs nothing, so must not stop a real run taking a delivery
+    let delivered_path = config
+        .dispatch
+        .journal
+        .as_ref()
+        .filter(|_| !args.dry_run)
+        .map(|journal| {
+            let mut path = journal.as_os_str().to_owned();
+            path.push(".delivered");
+            PathBuf::from(path)
+        });
+
+    let executor = Executor {
+        config,
+        run_as,
+        journal: journal.clone(),
+        scripts: Mutex::new(ScriptCache::new(config.dispatch.scripts_dirs.clone())),
+        shadow_scripts,
+        rescan_requested: signals.rescan_requested,
+        running: signals.running,
+        delivered: Delivered::load(
+            delivered_path,
+            Duration::from_secs(config.dispatch.redelivery_window_secs),
+        ),
+        events: config.dispatch.event_sink_url.clone().map(EventSink::new),
+        failures: config
+            .dispatch
+            .failure_webhook_url
+            .clone()
+            .map(FailureNotifier::new),
+        forward_headers,
+        options: exec::ExecOptions {
+            allocate_pty: config.dispatch.allocate_pty,
+            timeout: config.dispatch.script_timeout_secs.map(Duration::from_secs),
+            max_output_bytes: config.dispatch.max_output_bytes,
+            dry_run: args.dry_run,
+        },
+    };
+
+    if let Some(test_script) = args.test_script {
+        let (project, action) = (&test_script[0], &test_script[1]);
+        let action = match action.parse() {
+            Ok(action) => action,
+            Err(_err) => {
+                eprintln!("unknown action: {action}");
+                std::process::exit(2);
+            }
+        };
+        let command = match Command::new(action, project) {
+            Ok(command) => command,
+            Err(_err) => {
+                eprintln!("invalid project name: {project:?}");
+                std::process::exit(2);
+            }
+        };
+
+        match executor.run(&command) {
+            Some(exited) => {
+                println!("{command}: {}", exited.status);
+                std::process::exit(exited.status.code().unwrap_or(1));
+            }
+            None => {
+                println!("{command}: not run");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let policy = SchedulerPolicy::new(config);
+    let executor = Arc::new(executor);
+    let (scheduled, events) = mpsc::channel();
+    {
+        let executor = executor.clone();
+        let scheduled = scheduled.clone();
+        std::thread::spawn(move || run_scheduler(policy, executor, events, scheduled));
+    }
+
+    let replayed = match journal {
+        Some(journal) => replay(&journal, &executor.delivered, &scheduled)?,
+        None => HashSet::new(),
+    };
+    let replayed = Arc::new(Mutex::new(replayed));
+
+    let pipe = &config.dispatch.pipe;
+    match config.dispatch.transport {
+        Transport::Fifo => read_fifo(pipe, config, &scheduled, &replayed),
+        Transport::Unix => {
+            let listener = bind(pipe).map_err(|err| StartupError::Pipe(pipe.clone(), err))?;
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        PIPE_ERRORS.error(format_args!("unable to accept connection: {err:?}"));
+                        continue;
+                    }
+                };
+                let scheduled = scheduled.clone();
+                let replayed = replayed.clone();
+                std::thread::spawn(move || {
+                    let read = read_commands(&stream, Some(&stream), config, &scheduled, &replayed);
+                    if let Err(err) = read {
+                        PIPE_ERRORS.error(format_args!("error reading from socket: {err:?}"));
+                    }
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Reads commands from the fifo at `pipe` until it fails to open. Only
+/// returns on an error. The write end `open_fifo` holds means a fifo never
+/// reaches its end, so the read only stops on an error, or when the path is
+/// something else, and the pipe is reopened after a pause.
+fn read_fifo(
+    pipe: &Path,
+    config: &'static Config,
+    scheduled: &mpsc::Sender<Scheduled>,
+    replayed: &Mutex<HashSet<String>>,
+) -> Result<(), StartupError> {
+    let mut delay = REOPEN_DELAY;
     loop {
-        let pipe = std::fs::OpenOptions::new()
-            .read(true)
-            .open(&config.dispatch.pipe)
-            .unwrap();
-        let pipe = std::io::BufReader::new(pipe);
-
-        for line in pipe.lines() {
-            let line = match line {
-                Ok(line) => line,
-                Err(err) => {
-                    tracing::error!("error reading from pipe: {err:?}");
-                    continue;
+        let fifo = open_fifo(pipe).map_err(|err| StartupError::Pipe(pipe.to_path_buf(), err))?;
+        if let Err(err) = read_commands(fifo, None, config, scheduled, replayed) {
+            PIPE_ERRORS.error(format_args!("error reading from pipe: {err:?}"));
+        }
+        tracing::debug!("reopening pipe in {delay:?}");
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(MAX_REOPEN_DELAY);
+    }
+}
+
+/// A fifo is opened for writing as well as reading, which Linux allows
+/// without blocking. Holding a write end ourselves means writers coming and
+/// going never bring the read to its end, so there is no window between
+/// sessions where the fifo has no reader and a writer, or the server's
+/// health check, finds nobody there. Anything else at the path is only read.
+fn open_fifo(pipe: &Path) -> std::io::Result<std::fs::File> {
+    let is_fifo = std::fs::metadata(pipe)?.file_type().is_fifo();
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(is_fifo)
+        .open(pipe)
+}
+
+/// Schedules the commands a previous run left in the journal, handing back
+/// their ids so copies still waiting in the pipe are not run a second time.
+/// Their deliveries were taken but never run, so are taken again.
+fn replay(
+    journal: &Journal,
+    delivered: &Delivered,
+    scheduled: &mpsc::Sender<Scheduled>,
+) -> Result<HashSet<String>, StartupError> {
+    let pending = journal
+        .pending()
+        .map_err(|err| StartupError::Other(format!("unable to read journal: {err}")))?;
+
+    let mut replayed = HashSet::new();
+    for command in pending {
+        tracing::info!("replaying command from journal: {command}");
+        replayed.extend(command.journal_id.clone());
+        if let Some(id) = command.delivery_id.as_deref() {
+            delivered.forget(id);
+        }
+        let _ = scheduled.send(Scheduled::Received(Job {
+            command,
+            reply: None,
+        }));
+    }
+    Ok(replayed)
+}
+
+/// Takes over the socket path, a file left there by a previous run would
+/// otherwise fail the bind.
+fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => (),
+    }
+    UnixListener::bind(path)
+}
+
+/// Schedules each command read from one end of the transport until the
+/// writer closes it, returning how many lines were read. Commands asking for
+/// a reply get one on `socket`, if they came over one. Those already
+/// replayed from the journal are dropped. A read error ends the read, other
+/// than a line that is not UTF-8, which is skipped.
+fn read_commands(
+    reader: impl std::io::Read,
+    socket: Option<&UnixStream>,
+    config: &'static Config,
+    scheduled: &mpsc::Sender<Scheduled>,
+    replayed: &Mutex<HashSet<String>>,
+) -> std::io::Result<usize> {
+    let mut read = 0;
+    for line in std::io::BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                PIPE_ERRORS.error(format_args!("error reading from pipe: {err:?}"));
+                continue;
+            }
+            Err(err) => {
+                PIPE_ERRORS.flush();
+                return Err(err);
+            }
+        };
+        read += 1;
+
+        let command = match Command::from_wire(&line) {
+            Ok(command) => command,
+            Err(_err) => {
+                PIPE_ERRORS.error("unable to parse command");
+                continue;
+            }
+        };
+        let display_name = config.project_display_name(&command.project);
+        let _span =
+            tracing::info_span!("received", request_id = command.request_id.as_deref()).entered();
+        tracing::info!("got command: {command} ({display_name})");
+
+        if let Some(id) = command.journal_id.as_ref() {
+            if replayed.lock().unwrap().remove(id) {
+                tracing::info!("already replayed from journal, skipping: {command}");
+                continue;
+            }
+        }
+
+        let reply = socket
+            .filter(|_| command.reply)
+            .and_then(|socket| socket.try_clone().ok())
+            .map(Reply);
+        let _ = scheduled.send(Scheduled::Received(Job { command, reply }));
+    }
+
+    PIPE_ERRORS.flush();
+    Ok(read)
+}
+
+enum Scheduled {
+    Received(Job),
+    Finished(Command),
+}
+
+/// A command and, when its sender is waiting on it, where to report back.
+struct Job {
+    command: Command,
+    reply: Option<Reply>,
+}
+
+impl Borrow<Command> for Job {
+    fn borrow(&self) -> &Command {
+        &self.command
+    }
+}
+
+/// Only the end of stderr is sent back, the full output is in the log.
+const REPLY_STDERR_BYTES: usize = 4096;
+
+struct Reply(UnixStream);
+
+impl Reply {
+    /// `exited` is None when the script could not be run at all.
+    fn send(mut self, exited: Option<&Exited>) {
+        let result = CommandResult {
+            exit_code: exited.and_then(|exited| exited.status.code()),
+            stderr: exited
+                .map(|exited| tail(&exited.errors, REPLY_STDERR_BYTES).to_string())
+                .unwrap_or_default(),
+            duplicate: false,
+        };
+        self.write(&result);
+    }
+
+    /// Tells the sender its delivery was already taken and nothing ran.
+    fn duplicate(mut self) {
+        let result = CommandResult {
+            exit_code: None,
+            stderr: String::new(),
+            duplicate: true,
+        };
+        self.write(&result);
+    }
+
+    fn write(&mut self, result: &CommandResult) {
+        let line = format!("{}\n", result.to_wire());
+        if let Err(err) = self.0.write_all(line.as_bytes()) {
+            tracing::warn!("unable to report command result: {err}");
+        }
+    }
+}
+
+fn tail(text: &str, max_bytes: usize) -> &str {
+    let mut start = text.len().saturating_sub(max_bytes);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+/// Runs each admitted command on its own thread, so projects run in parallel
+/// while the policy keeps commands for one project from overlapping and the
+/// total under `max_concurrent_scripts`.
+fn run_scheduler(
+    mut policy: SchedulerPolicy,
+    executor: Arc<Executor>,
+    events: mpsc::Receiver<Scheduled>,
+    finished: mpsc::Sender<Scheduled>,
+) {
+    let mut queued = CommandQueue::default();
+
+    for event in events {
+        match event {
+            Scheduled::Received(job) => {
+                if let Some(id) = job.command.delivery_id.as_deref() {
+                    if !executor.delivered.insert(id) {
+                        tracing::info!("delivery {id} already taken, skipping: {}", job.command);
+                        executor.acknowledge(&job.command);
+                        if let Some(reply) = job.reply {
+                            reply.duplicate();
+                        }
+                        continue;
+                    }
                 }
-            };
-            tracing::info!("got line: {line}");
-
-            let command: config::Command = match line.parse() {
-                Ok(command) => command,
-                Err(_err) => {
-                    tracing::error!("unable to parse command");
-                    continue;
+                if let Admit::Queue = policy.admit(&job.command) {
+                    tracing::info!("queueing command: {}", job.command);
                 }
-            };
-            tracing::info!("got command: {command}");
-
-            if projects.contains(&command.project) {
-                let mut path = PathBuf::from(&config.dispatch.scripts_dir);
-                path.push(command.project);
-                path.push(command.action.to_string());
-
-                tracing::info!("executing command: {}", path.display());
-                let mut command = std::process::Command::new(path);
-                match command.status() {
-                    Ok(status) => {
-                        tracing::info!("command completed with status: {}", status);
+                queued.push(job);
+            }
+            Scheduled::Finished(command) => policy.finished(&command),
+        }
+
+        schedule(&mut policy, &mut queued, &executor, &finished);
+    }
+}
+
+fn schedule(
+    policy: &mut SchedulerPolicy,
+    queued: &mut CommandQueue<Job>,
+    executor: &Arc<Executor>,
+    finished: &mpsc::Sender<Scheduled>,
+) {
+    let mut deferred = Vec::new();
+
+    while let Some(job) = queued.pop() {
+        match policy.admit(&job.command) {
+            Admit::Run => {
+                // shutting down, whatever is left is replayed from the
+                // journal on the next start if there is one
+                let running = match executor.running.start() {
+                    Some(running) => running,
+                    None => {
+                        deferred.push(job);
+                        continue;
                     }
-                    Err(err) => tracing::error!("unabled to execute command: {err:?}"),
+                };
+                policy.started(&job.command);
+                let executor = executor.clone();
+                let finished = finished.clone();
+                std::thread::spawn(move || {
+                    let _running = running;
+                    let exited = executor.execute(&job.command);
+                    executor.acknowledge(&job.command);
+                    if let Some(reply) = job.reply {
+                        reply.send(exited.as_ref());
+                    }
+                    let _ = finished.send(Scheduled::Finished(job.command));
+                });
+            }
+            Admit::Queue => deferred.push(job),
+            Admit::Skip(reason) => {
+                tracing::error!("skipping command {}: {reason}", job.command);
+                executor.acknowledge(&job.command);
+                if let Some(reply) = job.reply {
+                    reply.send(None);
                 }
-            } else {
+            }
+        }
+    }
+
+    // deferred commands come out in priority then arrival order, so pushing
+    // them back in that order keeps their relative ordering
+    for job in deferred {
+        queued.push(job);
+    }
+}
+
+struct Executor {
+    config: &'static Config,
+    run_as: Option<RunAs>,
+    journal: Option<Journal>,
+    scripts: Mutex<ScriptCache>,
+    shadow_scripts: HashMap<String, Mutex<ScriptCache>>,
+    rescan_requested: Arc<AtomicBool>,
+    running: Arc<Running>,
+    delivered: Delivered,
+    events: Option<EventSink>,
+    failures: Option<FailureNotifier>,
+    forward_headers: HashSet<String>,
+    options: exec::ExecOptions,
+}
+
+impl Executor {
+    fn execute(&self, command: &Command) -> Option<Exited> {
+        let span = tracing::info_span!(
+            "execute",
+            %command,
+            request_id = command.request_id.as_deref(),
+            client = command.client.as_deref()
+        );
+        #[cfg(feature = "otel")]
+        telemetry::set_parent(&span, &command.trace_context);
+        let _entered = span.enter();
+
+        let started = Instant::now();
+        let exited = self.run(command);
+
+        if let Some(events) = self.events.as_ref().filter(|_| !self.options.dry_run) {
+            let status = exited.as_ref().map(|exited| exited.status);
+            events.send(DispatchEvent::new(command, status, started.elapsed()));
+        }
+        if let (Some(failures), Some(exited)) = (self.failures.as_ref(), exited.as_ref()) {
+            if !exited.status.success() {
+                failures.send(FailureNotification::new(command, exited));
+            }
+        }
+        exited
+    }
+
+    /// Removes the command from the journal once it has run, or will not be.
+    /// A dry run leaves it there for the next real run to replay.
+    fn acknowledge(&self, command: &Command) {
+        let (journal, id) = match (self.journal.as_ref(), command.journal_id.as_deref()) {
+            (Some(journal), Some(id)) if !self.options.dry_run => (journal, id),
+            _ => return,
+        };
+        if let Err(err) = journal.remove(id) {
+            tracing::error!("unable to remove {command} from journal: {err}");
+        }
+    }
+
+    fn run(&self, command: &Command) -> Option<Exited> {
+        let display_name = self.config.project_display_name(&command.project);
+        let rescan = self.rescan_requested.swap(false, Ordering::Relaxed);
+
+        let working_dir = self.config.dispatch.working_dir(&command.project);
+        if !working_dir.is_dir() {
+            tracing::error!(
+                "working directory {} for {display_name} does not exist, not running {command}",
+                working_dir.display()
+            );
+            return None;
+        }
+
+        if let Some(project) = self.config.projects.get(&command.project) {
+            if let Some(hook) = project.pre_dispatch_hook.as_ref() {
+                match self.run_pre_dispatch_hook(hook, project, command, display_name) {
+                    Ok(()) => (),
+                    Err(status) => return status,
+                }
+            }
+        }
+
+        if let Some(shadow_scripts) = self.shadow_scripts.get(&command.project) {
+            self.run_shadow(shadow_scripts, command, rescan, display_name);
+        }
+
+        let path = {
+            let mut scripts = self.scripts.lock().unwrap();
+            if rescan {
+                scripts.rescan();
+            }
+            scripts.resolve(command)
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => {
+                tracing::error!("no executable script for command: {command}");
+                return None;
+            }
+        };
+
+        tracing::info!("executing command for {display_name}: {}", path.display());
+        match exec::run(
+            self.process(path, command),
+            &self.options,
+            display_name,
+            command.payload.as_deref(),
+        ) {
+            Ok(exited) => {
+                tracing::info!(
+                    "command for {display_name} completed with status: {}",
+                    exited.status
+                );
+                Some(exited)
+            }
+            Err(err) => {
+                tracing::error!("unabled to execute command: {err:?}");
+                None
+            }
+        }
+    }
+
+    /// Hands back how the hook finished when it refuses the command, or None
+    /// when it cannot be run.
+    fn run_pre_dispatch_hook(
+        &self,
+        hook: &Path,
+        project: &ProjectConfig,
+        command: &Command,
+        display_name: &str,
+    ) -> Result<(), Option<Exited>> {
+        tracing::info!(
+            "running pre-dispatch hook for {display_name}: {}",
+            hook.display()
+        );
+        let options = exec::ExecOptions {
+            timeout: Some(Duration::from_secs(project.pre_dispatch_timeout_secs)),
+            ..self.options.clone()
+        };
+        let label = format!("{display_name} (pre-dispatch)");
+
+        match exec::run(
+            self.process(hook.to_path_buf(), command),
+            &options,
+            &label,
+            command.payload.as_deref(),
+        ) {
+            Ok(exited) if exited.status.success() => Ok(()),
+            Ok(exited) => {
                 tracing::error!(
-                    "recieved command for unconfigured project: {}",
-                    command.project
+                    "pre-dispatch hook aborted command {command}: {}",
+                    exited.status
                 );
+                Err(Some(exited))
+            }
+            Err(err) => {
+                tracing::error!("unable to run pre-dispatch hook for {display_name}: {err:?}");
+                Err(None)
+            }
+        }
+    }
+
+    /// Runs the shadow script on its own thread so it can neither hold up the
+    /// real script nor feed into the dispatch event for it.
+    fn run_shadow(
+        &self,
+        shadow_scripts: &Mutex<ScriptCache>,
+        command: &Command,
+        rescan: bool,
+        display_name: &str,
+    ) {
+        let path = {
+            let mut scripts = shadow_scripts.lock().unwrap();
+            if rescan {
+                scripts.rescan();
+            }
+            scripts.resolve(command)
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        tracing::info!(
+            "executing shadow command for {display_name}: {}",
+            path.display()
+        );
+        let process = self.process(path, command);
+        let options = self.options.clone();
+        let label = format!("{display_name} (shadow)");
+        let payload = command.payload.clone();
+        std::thread::spawn(move || {
+            match exec::run(process, &options, &label, payload.as_deref()) {
+                Ok(exited) => tracing::info!(
+                    "command for {label} completed with status: {}",
+                    exited.status
+                ),
+                Err(err) => tracing::error!("unable to execute command for {label}: {err:?}"),
+            }
+        });
+    }
+
+    fn process(&self, path: PathBuf, command: &Command) -> std::process::Command {
+        let mut process = std::process::Command::new(path);
+        if let Some(project) = self.config.projects.get(&command.project) {
+            let action = command.action.to_string();
+            process.args(project.args.iter().map(|arg| {
+                arg.replace("{project}", &command.project)
+                    .replace("{action}", &action)
+            }));
+        }
+        process.current_dir(self.config.dispatch.working_dir(&command.project));
+        if let Some(run_as) = self.run_as.as_ref() {
+            run_as.apply(&mut process);
+        }
+        process.env("WEBHOOK_ACTION", command.action.to_string());
+        if let Some(git_ref) = command.git_ref.as_ref() {
+            process.env("WEBHOOK_REF", git_ref);
+        }
+        if let Some(sha) = command.sha.as_ref() {
+            process.env("WEBHOOK_SHA", sha);
+        }
+        if let Some(pusher) = command.pusher.as_ref() {
+            process.env("WEBHOOK_PUSHER", pusher);
+        }
+        if let Some(environment) = command.environment.as_ref() {
+            process.env("WEBHOOK_ENV", environment);
+        }
+        if let Some(delivery_id) = command.delivery_id.as_ref() {
+            process.env("WEBHOOK_DELIVERY_ID", delivery_id);
+        }
+        for (name, value) in command.headers.iter() {
+            if self.forward_headers.contains(&name.to_ascii_lowercase()) {
+                process.env(header_env_name(name), value);
+            }
+        }
+        process
+    }
+}
+
+fn header_env_name(header: &str) -> String {
+    let normalized: String = header
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("WEBHOOK_HEADER_{normalized}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    fn write_line(pipe: &Path, line: &str) {
+        let mut writer = std::fs::OpenOptions::new().append(true).open(pipe).unwrap();
+        writeln!(writer, "{line}").unwrap();
+    }
+
+    /// An executable `scripts/<project>/<action>` running `body`.
+    fn script(dir: &Path, project: &str, action: &str, body: &str) {
+        let project = dir.join("scripts").join(project);
+        std::fs::create_dir_all(&project).unwrap();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .mode(0o755)
+            .open(project.join(action))
+            .unwrap()
+            .write_all(format!("#!/bin/sh\n{body}\n").as_bytes())
+            .unwrap();
+    }
+
+    fn executor(config: &'static Config) -> Executor {
+        Executor {
+            config,
+            run_as: None,
+            journal: None,
+            scripts: Mutex::new(ScriptCache::new(config.dispatch.scripts_dirs.clone())),
+            shadow_scripts: HashMap::new(),
+            rescan_requested: Arc::default(),
+            running: Arc::default(),
+            delivered: Delivered::load(None, Duration::from_secs(60)),
+            events: None,
+            failures: None,
+            forward_headers: HashSet::new(),
+            options: exec::ExecOptions {
+                allocate_pty: false,
+                timeout: None,
+                max_output_bytes: 1024,
+                dry_run: false,
+            },
+        }
+    }
+
+    fn job(command: Command) -> Scheduled {
+        Scheduled::Received(Job {
+            command,
+            reply: None,
+        })
+    }
+
+    fn received(events: &mpsc::Receiver<Scheduled>) -> Command {
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(Scheduled::Received(job)) => job.command,
+            Ok(Scheduled::Finished(command)) => panic!("unexpected finished {command}"),
+            Err(err) => panic!("no command received: {err}"),
+        }
+    }
+
+    #[test]
+    fn fifo_keeps_a_reader_between_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let pipe = dir.path().join("pipe");
+        test_support::mkfifo(&pipe);
+
+        let (scheduled, events) = mpsc::channel();
+        std::thread::spawn(move || {
+            let replayed = Mutex::new(HashSet::new());
+            read_fifo(&config.dispatch.pipe, config, &scheduled, &replayed)
+        });
+
+        write_line(&pipe, "deploy proj");
+        assert_eq!(received(&events).to_string(), "deploy proj");
+
+        // what the server's health check does, which must never find the
+        // fifo without a reader once the first writer has gone
+        for _ in 0..100 {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&pipe)
+                .expect("fifo has a reader");
+        }
+
+        write_line(&pipe, "rollback proj");
+        assert_eq!(received(&events).to_string(), "rollback proj");
+    }
+
+    #[test]
+    fn pipe_failing_every_read_is_reopened_with_backoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        // opens fine but fails every read
+        std::fs::create_dir(&config.dispatch.pipe).unwrap();
+
+        let captured = test_support::Captured::default();
+        let logs = captured.clone();
+        let (scheduled, _events) = mpsc::channel();
+        std::thread::spawn(move || {
+            let replayed = Mutex::new(HashSet::new());
+            test_support::capture_logs(&logs, || {
+                read_fifo(&config.dispatch.pipe, config, &scheduled, &replayed)
+            })
+        });
+
+        std::thread::sleep(Duration::from_millis(200));
+        let reopens: Vec<_> = captured
+            .contents()
+            .lines()
+            .filter_map(|line| line.split_once("reopening pipe in "))
+            .map(|(_, delay)| delay.to_string())
+            .collect();
+        assert_eq!(reopens[..4], ["10ms", "20ms", "40ms", "80ms"]);
+    }
+
+    #[test]
+    fn redelivered_command_runs_the_script_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let runs = dir.path().join("runs");
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!("echo ran >> {}", runs.display()),
+        );
+
+        let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+        command.delivery_id = Some("delivery".into());
+        let (scheduled, events) = mpsc::channel();
+        let (finished, done) = mpsc::channel();
+        scheduled.send(job(command.clone())).unwrap();
+        scheduled.send(job(command)).unwrap();
+        drop(scheduled);
+
+        let executor = Arc::new(executor(config));
+        run_scheduler(SchedulerPolicy::new(config), executor, events, finished);
+        done.recv_timeout(Duration::from_secs(5))
+            .expect("first command ran");
+        assert!(done.recv_timeout(Duration::from_millis(200)).is_err());
+
+        assert_eq!(std::fs::read_to_string(runs).unwrap(), "ran\n");
+    }
+
+    #[test]
+    fn failing_script_sends_a_notification() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        script(dir.path(), "proj", "deploy", "echo broken >&2\nexit 3");
+        let (url, bodies) = test_support::mock_server();
+
+        let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+        command.request_id = Some("req-1".into());
+        command.client = Some("abc".into());
+        let executor = Executor {
+            failures: Some(FailureNotifier::new(url)),
+            ..executor(config)
+        };
+        executor.execute(&command);
+
+        let body = bodies.recv_timeout(Duration::from_secs(5)).unwrap();
+        let mut body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["timestamp"].as_u64().unwrap() > 0);
+        body.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "project": "proj",
+                "action": "deploy",
+                "exit_code": 3,
+                "timed_out": false,
+                "stderr": "broken\n",
+                "request_id": "req-1",
+                "client": "abc",
+            })
+        );
+    }
+
+    #[test]
+    fn executed_command_is_logged_with_its_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        script(dir.path(), "proj", "deploy", "true");
+
+        let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+        command.client = Some("abc".into());
+        let captured = test_support::Captured::default();
+        test_support::capture_logs(&captured, || executor(config).execute(&command));
+
+        let logs = captured.contents();
+        let logged = |message: &str| {
+            logs.lines()
+                .find(|line| line.contains(message))
+                .unwrap_or_else(|| panic!("{message:?} not logged in {logs}"))
+                .to_string()
+        };
+        assert!(logged("executing command").contains("client=\"abc\""));
+        assert!(logged("completed with status").contains("client=\"abc\""));
+    }
+
+    #[test]
+    fn clients_sharing_a_project_are_told_apart_in_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!(
+            "{CONFIG}\n[clients.repos]\nsecret = \"other\"\nproject = \"{{repo}}\"\npermissions = [\"deploy\"]\n"
+        );
+        let config = test_support::config(&toml, dir.path());
+        script(dir.path(), "proj", "deploy", "true");
+
+        let executor = executor(config);
+        let captured = test_support::Captured::default();
+        for client in ["abc", "repos"] {
+            let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+            command.client = Some(client.into());
+            test_support::capture_logs(&captured, || executor.execute(&command));
+        }
+
+        let logs = captured.contents();
+        let clients: Vec<_> = logs
+            .lines()
+            .filter(|line| line.contains("executing command"))
+            .filter_map(|line| line.split_once("client=\"")?.1.split_once('"'))
+            .map(|(client, _)| client)
+            .collect();
+        assert_eq!(clients, ["abc", "repos"], "{logs}");
+    }
+
+    /// Runs the commands through the scheduler and hands back the lines the
+    /// scripts appended to `log`, once there are `lines` of them.
+    fn run_logged(
+        config: &'static Config,
+        commands: &[Command],
+        log: &Path,
+        lines: usize,
+    ) -> Vec<String> {
+        let (scheduled, events) = mpsc::channel();
+        for command in commands {
+            scheduled.send(job(command.clone())).unwrap();
+        }
+        let executor = Arc::new(executor(config));
+        let policy = SchedulerPolicy::new(config);
+        std::thread::spawn(move || run_scheduler(policy, executor, events, scheduled));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let logged = std::fs::read_to_string(log).unwrap_or_default();
+            let logged: Vec<_> = logged.lines().map(String::from).collect();
+            if logged.len() >= lines {
+                return logged;
+            }
+            assert!(Instant::now() < deadline, "only logged {logged:?}");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn projects_run_concurrently_but_one_project_runs_serially() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("project = \"proj\"", "project = [\"proj\", \"site\"]");
+        let config = test_support::config(&toml, dir.path());
+        let log = dir.path().join("log");
+        for project in ["proj", "site"] {
+            let body = format!(
+                "echo start {project} >> {log}\nsleep 0.3\necho end {project} >> {log}",
+                log = log.display()
+            );
+            script(dir.path(), project, "deploy", &body);
+        }
+        let deploy = |project| Command::new(config::Action::Deploy, project).unwrap();
+
+        let logged = run_logged(config, &[deploy("proj"), deploy("site")], &log, 4);
+        assert!(
+            logged[..2].iter().all(|line| line.starts_with("start")),
+            "{logged:?}"
+        );
+
+        std::fs::remove_file(&log).unwrap();
+        let logged = run_logged(config, &[deploy("proj"), deploy("proj")], &log, 4);
+        assert_eq!(logged, ["start proj", "end proj", "start proj", "end proj"]);
+    }
+
+    #[test]
+    fn command_round_trips_over_a_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let listener = bind(&config.dispatch.pipe).unwrap();
+
+        let (scheduled, events) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let replayed = Mutex::new(HashSet::new());
+            read_commands(&stream, Some(&stream), config, &scheduled, &replayed)
+        });
+
+        let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+        command.payload = Some(br#"{"ref": "refs/heads/main"}"#.to_vec());
+        let mut stream = UnixStream::connect(&config.dispatch.pipe).unwrap();
+        writeln!(stream, "{}", command.to_wire()).unwrap();
+
+        assert_eq!(received(&events).to_wire(), command.to_wire());
+    }
+
+    #[test]
+    fn script_sees_the_push_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let env = dir.path().join("env");
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!(
+                "echo \"$WEBHOOK_ACTION ${{WEBHOOK_REF-unset}} ${{WEBHOOK_SHA-unset}} ${{WEBHOOK_PUSHER-unset}}\" >> {}",
+                env.display()
+            ),
+        );
+
+        let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+        command.git_ref = Some("refs/heads/main".into());
+        command.sha = Some("abc123".into());
+        command.pusher = Some("octocat".into());
+        executor(config).execute(&command);
+        assert_eq!(
+            std::fs::read_to_string(&env).unwrap(),
+            "deploy refs/heads/main abc123 octocat\n"
+        );
+
+        // missing fields leave the variables unset rather than empty
+        std::fs::remove_file(&env).unwrap();
+        executor(config).execute(&Command::new(config::Action::Deploy, "proj").unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&env).unwrap(),
+            "deploy unset unset unset\n"
+        );
+    }
+
+    #[test]
+    fn dry_run_logs_the_script_without_running_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let ran = dir.path().join("ran");
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!("touch {}", ran.display()),
+        );
+
+        let base = executor(config);
+        let executor = Executor {
+            options: exec::ExecOptions {
+                dry_run: true,
+                ..base.options
+            },
+            ..base
+        };
+        let command = Command::new(config::Action::Deploy, "proj").unwrap();
+        let captured = test_support::Captured::default();
+        test_support::capture_logs(&captured, || executor.execute(&command));
+
+        assert!(!ran.exists(), "script was run");
+        let logs = captured.contents();
+        let path = dir.path().join("scripts/proj/deploy");
+        assert!(
+            logs.lines()
+                .any(|line| line.contains("dry run") && line.contains(&*path.to_string_lossy())),
+            "{logs}"
+        );
+    }
+
+    #[test]
+    fn failed_script_is_reported_back_over_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            "echo deploy broke >&2\nexit 3",
+        );
+        let listener = bind(&config.dispatch.pipe).unwrap();
+
+        let (scheduled, events) = mpsc::channel();
+        let finished = scheduled.clone();
+        let executor = Arc::new(executor(config));
+        let policy = SchedulerPolicy::new(config);
+        std::thread::spawn(move || run_scheduler(policy, executor, events, finished));
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let replayed = Mutex::new(HashSet::new());
+            read_commands(&stream, Some(&stream), config, &scheduled, &replayed)
+        });
+
+        let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+        command.reply = true;
+        let mut stream = UnixStream::connect(&config.dispatch.pipe).unwrap();
+        writeln!(stream, "{}", command.to_wire()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut reply = String::new();
+        std::io::BufReader::new(stream)
+            .read_line(&mut reply)
+            .unwrap();
+        let result = config::CommandResult::from_wire(&reply).unwrap();
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.stderr, "deploy broke\n");
+    }
+
+    #[test]
+    fn taken_delivery_is_reported_back_as_a_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let runs = dir.path().join("runs");
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!("echo ran >> {}", runs.display()),
+        );
+        let listener = bind(&config.dispatch.pipe).unwrap();
+
+        let (scheduled, events) = mpsc::channel();
+        let finished = scheduled.clone();
+        let executor = Arc::new(executor(config));
+        let policy = SchedulerPolicy::new(config);
+        std::thread::spawn(move || run_scheduler(policy, executor, events, finished));
+        std::thread::spawn(move || {
+            let replayed = Mutex::new(HashSet::new());
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let _ = read_commands(&stream, Some(&stream), config, &scheduled, &replayed);
             }
+        });
+
+        let send = || {
+            let mut command = Command::new(config::Action::Deploy, "proj").unwrap();
+            command.reply = true;
+            command.delivery_id = Some("d1".into());
+            let mut stream = UnixStream::connect(&config.dispatch.pipe).unwrap();
+            writeln!(stream, "{}", command.to_wire()).unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut reply = String::new();
+            std::io::BufReader::new(stream)
+                .read_line(&mut reply)
+                .unwrap();
+            config::CommandResult::from_wire(&reply).unwrap()
+        };
+
+        let first = send();
+        assert_eq!(first.exit_code, Some(0));
+        assert!(!first.duplicate);
+        let second = send();
+        assert!(second.duplicate);
+        assert_eq!(second.exit_code, None);
+        assert_eq!(std::fs::read_to_string(runs).unwrap(), "ran\n");
+    }
+
+    #[test]
+    fn script_runs_in_the_configured_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace(
+            "[clients.abc]",
+            "working_dir = \"{dir}/checkouts/{project}\"\n\n[clients.abc]",
+        );
+        let config = test_support::config(&toml, dir.path());
+        let cwd = dir.path().join("cwd");
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!("pwd -P > {}", cwd.display()),
+        );
+        let command = Command::new(config::Action::Deploy, "proj").unwrap();
+
+        // a missing working dir skips the script rather than running it elsewhere
+        executor(config).execute(&command);
+        assert!(!cwd.exists());
+
+        let checkout = dir.path().join("checkouts/proj");
+        std::fs::create_dir_all(&checkout).unwrap();
+        executor(config).execute(&command);
+        let ran_in = std::fs::read_to_string(&cwd).unwrap();
+        assert_eq!(Path::new(ran_in.trim()), checkout.canonicalize().unwrap());
+    }
+
+    #[test]
+    fn configured_args_reach_the_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace(
+            "[clients.abc]",
+            "[projects.proj]\npermissions = [\"deploy\"]\nargs = [\"--env=prod\", \"{project} {action}\"]\n\n[clients.abc]",
+        );
+        let config = test_support::config(&toml, dir.path());
+        let argv = dir.path().join("argv");
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!("printf '%s\\n' \"$@\" > {}", argv.display()),
+        );
+
+        executor(config).execute(&Command::new(config::Action::Deploy, "proj").unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&argv).unwrap(),
+            "--env=prod\nproj deploy\n"
+        );
+    }
+
+    #[test]
+    fn running_scripts_never_exceed_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let projects = ["proj", "site", "docs", "blog"];
+        let toml = CONFIG
+            .replace(
+                "[clients.abc]",
+                "max_concurrent_scripts = 2\n\n[clients.abc]",
+            )
+            .replace(
+                "project = \"proj\"",
+                "project = [\"proj\", \"site\", \"docs\", \"blog\"]",
+            );
+        let config = test_support::config(&toml, dir.path());
+        let log = dir.path().join("log");
+        for project in projects {
+            let body = format!(
+                "echo start >> {log}\nsleep 0.2\necho end >> {log}",
+                log = log.display()
+            );
+            script(dir.path(), project, "deploy", &body);
+        }
+        let commands: Vec<_> = projects
+            .iter()
+            .map(|project| Command::new(config::Action::Deploy, project).unwrap())
+            .collect();
+
+        let logged = run_logged(config, &commands, &log, 8);
+        let mut running = 0;
+        let mut most = 0;
+        for line in logged {
+            running += if line == "start" { 1 } else { -1 };
+            most = most.max(running);
         }
+        assert_eq!(most, 2);
+    }
+
+    #[test]
+    fn script_past_its_timeout_is_killed_and_the_queue_moves_on() {
+        let dir = tempfile::tempdir().unwrap();
+        // one script at a time, so the second waits on the first
+        let toml = CONFIG
+            .replace(
+                "[clients.abc]",
+                "max_concurrent_scripts = 1\n\n[clients.abc]",
+            )
+            .replace("project = \"proj\"", "project = [\"proj\", \"site\"]");
+        let config = test_support::config(&toml, dir.path());
+        let (finished, ran) = (dir.path().join("finished"), dir.path().join("ran"));
+        script(
+            dir.path(),
+            "proj",
+            "deploy",
+            &format!("sleep 30\ntouch {}", finished.display()),
+        );
+        script(
+            dir.path(),
+            "site",
+            "deploy",
+            &format!("touch {}", ran.display()),
+        );
+
+        let (scheduled, events) = mpsc::channel();
+        for project in ["proj", "site"] {
+            let command = Command::new(config::Action::Deploy, project).unwrap();
+            scheduled.send(job(command)).unwrap();
+        }
+        let base = executor(config);
+        let executor = Arc::new(Executor {
+            options: exec::ExecOptions {
+                timeout: Some(Duration::from_secs(1)),
+                ..base.options
+            },
+            ..base
+        });
+        let policy = SchedulerPolicy::new(config);
+        let started = Instant::now();
+        std::thread::spawn(move || run_scheduler(policy, executor, events, scheduled));
+
+        while !ran.exists() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "second command never ran"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(!finished.exists(), "timed out script ran to completion");
+    }
+
+    #[test]
+    fn one_shot_runs_write_no_pid_file() {
+        let args = |extra: &[&str]| {
+            let base = ["dispatch", "--pid-file", "dispatch.pid"];
+            Args::parse_from(base.iter().chain(extra))
+        };
+        assert!(!args(&[]).one_shot());
+        assert!(args(&["--test-script", "proj", "deploy"]).one_shot());
+        assert!(args(&["--print-effective-config"]).one_shot());
     }
 }
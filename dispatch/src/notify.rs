@@ -0,0 +1,95 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use config::{Action, Command};
+
+use crate::exec::Exited;
+
+const QUEUE_SIZE: usize = 16;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+/// Only the end of stderr is sent, where the reason for a failure usually is.
+const STDERR_TAIL_BYTES: usize = 2048;
+
+#[derive(Debug, Serialize)]
+pub struct FailureNotification {
+    project: String,
+    action: Action,
+    /// None when the script was killed, by a signal or for timing out.
+    exit_code: Option<i32>,
+    timed_out: bool,
+    stderr: String,
+    request_id: Option<String>,
+    /// The key of the client whose request ran the script.
+    client: Option<String>,
+    timestamp: u64,
+}
+
+impl FailureNotification {
+    pub fn new(command: &Command, exited: &Exited) -> Self {
+        FailureNotification {
+            project: command.project.clone(),
+            action: command.action,
+            exit_code: exited.status.code(),
+            timed_out: exited.timed_out,
+            stderr: tail(&exited.errors, STDERR_TAIL_BYTES).to_string(),
+            request_id: command.request_id.clone(),
+            client: command.client.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Posts a notification to `failure_webhook_url` for every script that fails,
+/// once and from a background thread, so a slow receiver only ever costs
+/// the notification.
+pub struct FailureNotifier {
+    tx: SyncSender<FailureNotification>,
+}
+
+impl FailureNotifier {
+    pub fn new(url: String) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<FailureNotification>(QUEUE_SIZE);
+
+        std::thread::spawn(move || {
+            let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+            for notification in rx {
+                if let Err(err) = agent.post(&url).send_json(&notification) {
+                    tracing::warn!(
+                        "unable to send failure notification for {} {}: {err}",
+                        notification.action,
+                        notification.project
+                    );
+                }
+            }
+        });
+
+        FailureNotifier { tx }
+    }
+
+    pub fn send(&self, notification: FailureNotification) {
+        match self.tx.try_send(notification) {
+            Ok(()) => (),
+            Err(TrySendError::Full(notification)) => tracing::warn!(
+                "failure notification queue full, dropping notification for {} {}",
+                notification.action,
+                notification.project
+            ),
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::error!("failure notification worker has stopped")
+            }
+        }
+    }
+}
+
+fn tail(s: &str, max_bytes: usize) -> &str {
+    let mut start = s.len().saturating_sub(max_bytes);
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
@@ -0,0 +1,209 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+
+use config::{Command, Config};
+
+pub enum Admit {
+    Run,
+    Queue,
+    Skip(SkipReason),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum SkipReason {
+    UnknownProject,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::UnknownProject => write!(f, "unconfigured project"),
+        }
+    }
+}
+
+/// Owns every decision about whether a command may run right now, so the
+/// execution loop only has to act on the answer.
+pub struct SchedulerPolicy {
+    config: &'static Config,
+    running: HashSet<String>,
+}
+
+impl SchedulerPolicy {
+    pub fn new(config: &'static Config) -> Self {
+        SchedulerPolicy {
+            config,
+            running: HashSet::new(),
+        }
+    }
+
+    pub fn admit(&self, command: &Command) -> Admit {
+        let known = self.config.has_project(&command.project);
+        let at_limit = self
+            .config
+            .dispatch
+            .max_concurrent_scripts
+            .is_some_and(|max| self.running.len() >= max.get());
+        if !known {
+            Admit::Skip(SkipReason::UnknownProject)
+        } else if self.running.contains(&command.project) || at_limit {
+            Admit::Queue
+        } else {
+            Admit::Run
+        }
+    }
+
+    pub fn started(&mut self, command: &Command) {
+        self.running.insert(command.project.clone());
+    }
+
+    pub fn finished(&mut self, command: &Command) {
+        self.running.remove(&command.project);
+    }
+}
+
+/// Commands waiting to run, highest priority first and in arrival order within
+/// a priority.
+pub struct CommandQueue<T> {
+    heap: BinaryHeap<Queued<T>>,
+    next_seq: u64,
+}
+
+impl<T> Default for CommandQueue<T> {
+    fn default() -> Self {
+        CommandQueue {
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<T: Borrow<Command>> CommandQueue<T> {
+    pub fn push(&mut self, item: T) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(Queued { seq, item });
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.heap.pop().map(|queued| queued.item)
+    }
+}
+
+struct Queued<T> {
+    seq: u64,
+    item: T,
+}
+
+impl<T: Borrow<Command>> Ord for Queued<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.item
+            .borrow()
+            .priority
+            .cmp(&other.item.borrow().priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T: Borrow<Command>> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Borrow<Command>> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Borrow<Command>> Eq for Queued<T> {}
+
+#[cfg(test)]
+mod tests {
+    use config::Action;
+
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn projects_table_entries_are_known() {
+        let toml = test_support::CONFIG.replace(
+            "[clients.abc]",
+            "[projects.tools]\npermissions = [\"deploy\"]\n\n[clients.abc]",
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let policy = SchedulerPolicy::new(test_support::config(&toml, dir.path()));
+
+        let command = Command::new(Action::Deploy, "tools").unwrap();
+        assert!(matches!(policy.admit(&command), Admit::Run));
+        let command = Command::new(Action::Deploy, "missing").unwrap();
+        assert!(matches!(
+            policy.admit(&command),
+            Admit::Skip(SkipReason::UnknownProject)
+        ));
+    }
+
+    #[test]
+    fn rollback_runs_before_an_earlier_deploy() {
+        let mut queue = CommandQueue::default();
+        queue.push(Command::new(Action::Deploy, "proj").unwrap());
+        queue.push(Command::new(Action::Rollback, "proj").unwrap());
+
+        assert_eq!(queue.pop().unwrap().action, Action::Rollback);
+        assert_eq!(queue.pop().unwrap().action, Action::Deploy);
+        assert!(queue.pop().is_none());
+    }
+
+    fn two_projects() -> String {
+        test_support::CONFIG.replace("project = \"proj\"", "project = [\"proj\", \"site\"]")
+    }
+
+    #[test]
+    fn one_command_per_project_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut policy = SchedulerPolicy::new(test_support::config(&two_projects(), dir.path()));
+        let proj = Command::new(Action::Deploy, "proj").unwrap();
+        let site = Command::new(Action::Deploy, "site").unwrap();
+
+        policy.started(&proj);
+        assert!(matches!(policy.admit(&proj), Admit::Queue));
+        assert!(matches!(policy.admit(&site), Admit::Run));
+
+        policy.finished(&proj);
+        assert!(matches!(policy.admit(&proj), Admit::Run));
+    }
+
+    #[test]
+    fn max_concurrent_scripts_queues_other_projects() {
+        let toml = two_projects().replace(
+            "[clients.abc]",
+            "max_concurrent_scripts = 1\n\n[clients.abc]",
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let mut policy = SchedulerPolicy::new(test_support::config(&toml, dir.path()));
+        let proj = Command::new(Action::Deploy, "proj").unwrap();
+        let site = Command::new(Action::Deploy, "site").unwrap();
+
+        policy.started(&proj);
+        assert!(matches!(policy.admit(&site), Admit::Queue));
+
+        policy.finished(&proj);
+        assert!(matches!(policy.admit(&site), Admit::Run));
+    }
+
+    #[test]
+    fn equal_priorities_run_in_arrival_order() {
+        let mut queue = CommandQueue::default();
+        for project in ["first", "second", "third"] {
+            queue.push(Command::new(Action::Deploy, project).unwrap());
+        }
+        queue.push(Command::new(Action::Rollback, "urgent").unwrap());
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|command| command.project)
+            .collect();
+        assert_eq!(order, ["urgent", "first", "second", "third"]);
+    }
+}
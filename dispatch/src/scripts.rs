@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use config::Command;
+
+type ScriptKey = (String, Option<String>, String);
+
+/// Resolved, executable scripts under the scripts dirs, keyed by project,
+/// environment and action. Environment specific scripts live one directory
+/// deeper, at `<project>/<environment>/<action>`. The dirs are searched in
+/// order, a script in an earlier one hides the same script in a later one.
+pub struct ScriptCache {
+    roots: Vec<PathBuf>,
+    scripts: HashMap<ScriptKey, PathBuf>,
+}
+
+impl ScriptCache {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        let mut cache = ScriptCache {
+            roots,
+            scripts: HashMap::new(),
+        };
+        cache.rescan();
+        cache
+    }
+
+    pub fn rescan(&mut self) {
+        self.scripts.clear();
+
+        for root in self.roots.clone() {
+            self.scan_root(&root);
+        }
+
+        tracing::info!("found {} scripts", self.scripts.len());
+    }
+
+    fn scan_root(&mut self, root: &Path) {
+        let projects = match std::fs::read_dir(root) {
+            Ok(projects) => projects,
+            Err(err) => {
+                tracing::error!("unable to scan scripts dir {}: {err:?}", root.display());
+                return;
+            }
+        };
+
+        for project in projects.flatten() {
+            let project_name = project.file_name().to_string_lossy().into_owned();
+            let entries = match std::fs::read_dir(project.path()) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if path.is_dir() {
+                    self.scan_environment(&project_name, name, &path);
+                } else if is_executable(&path) {
+                    self.scripts
+                        .entry((project_name.clone(), None, name))
+                        .or_insert(path);
+                }
+            }
+        }
+    }
+
+    fn scan_environment(&mut self, project: &str, environment: String, dir: &Path) {
+        let actions = match std::fs::read_dir(dir) {
+            Ok(actions) => actions,
+            Err(_) => return,
+        };
+
+        for action in actions.flatten() {
+            let path = action.path();
+            if is_executable(&path) {
+                let action_name = action.file_name().to_string_lossy().into_owned();
+                self.scripts
+                    .entry((project.to_string(), Some(environment.clone()), action_name))
+                    .or_insert(path);
+            }
+        }
+    }
+
+    /// Prefers a script specific to the command's environment, falling back to
+    /// the project's default script for the action. Whatever is found must
+    /// still be a regular executable file inside the scripts dir it was found
+    /// in once symlinks are followed.
+    pub fn resolve(&mut self, command: &Command) -> Option<PathBuf> {
+        if !is_plain_name(&command.project) {
+            tracing::error!(
+                "refusing to resolve script for project: {}",
+                command.project
+            );
+            return None;
+        }
+
+        let path = self.find(command)?;
+        let root = self.roots.iter().find(|root| path.starts_with(root))?;
+        if !is_contained(root, &path) {
+            tracing::error!(
+                "refusing to run script outside of {}: {}",
+                root.display(),
+                path.display()
+            );
+            return None;
+        }
+        tracing::info!(
+            "resolved {} {} from {}",
+            command.action,
+            command.project,
+            root.display()
+        );
+        Some(path)
+    }
+
+    fn find(&mut self, command: &Command) -> Option<PathBuf> {
+        let action = command.action.to_string();
+
+        if let Some(environment) = command.environment.as_deref() {
+            if !is_plain_name(environment) {
+                tracing::error!("refusing to resolve script for environment: {environment}");
+                return None;
+            }
+
+            let key = (
+                command.project.clone(),
+                Some(environment.to_string()),
+                action.clone(),
+            );
+            if let Some(path) = self.lookup(key) {
+                return Some(path);
+            }
+        }
+
+        self.lookup((command.project.clone(), None, action))
+    }
+
+    /// Falls back to checking the filesystem for scripts added since the last
+    /// scan, only removed or no longer executable scripts need a rescan.
+    fn lookup(&mut self, key: ScriptKey) -> Option<PathBuf> {
+        if let Some(path) = self.scripts.get(&key) {
+            return Some(path.clone());
+        }
+
+        let path = self.roots.iter().find_map(|root| {
+            let mut path = root.join(&key.0);
+            if let Some(environment) = key.1.as_ref() {
+                path.push(environment);
+            }
+            path.push(&key.2);
+            is_executable(&path).then_some(path)
+        })?;
+
+        self.scripts.insert(key, path.clone());
+        Some(path)
+    }
+}
+
+fn is_contained(root: &Path, path: &Path) -> bool {
+    let (root, path) = match (root.canonicalize(), path.canonicalize()) {
+        (Ok(root), Ok(path)) => (root, path),
+        _ => return false,
+    };
+    path.starts_with(root) && is_executable(&path)
+}
+
+/// Projects and environments come off the wire, so must not be able to
+/// escape a scripts dir or the project's directory.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use config::Action;
+
+    use super::*;
+
+    fn executable(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .mode(0o755)
+            .open(path)
+            .unwrap();
+    }
+
+    fn deploy(project: &str) -> Command {
+        let mut command = Command::new(Action::Deploy, "proj").unwrap();
+        command.project = project.to_string();
+        command
+    }
+
+    #[test]
+    fn script_in_the_project_dir_resolves() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = dir.path().join("scripts");
+        executable(&scripts.join("proj/deploy"));
+
+        let mut cache = ScriptCache::new(vec![scripts.clone()]);
+        assert_eq!(
+            cache.resolve(&deploy("proj")),
+            Some(scripts.join("proj/deploy"))
+        );
+    }
+
+    #[test]
+    fn project_escaping_the_scripts_dir_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = dir.path().join("scripts");
+        std::fs::create_dir_all(&scripts).unwrap();
+        executable(&dir.path().join("escape/deploy"));
+
+        let mut cache = ScriptCache::new(vec![scripts]);
+        assert_eq!(cache.resolve(&deploy("../escape")), None);
+        assert_eq!(cache.resolve(&deploy("proj/../../escape")), None);
+    }
+
+    #[test]
+    fn symlink_out_of_the_scripts_dir_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = dir.path().join("scripts");
+        std::fs::create_dir_all(scripts.join("proj")).unwrap();
+        executable(&dir.path().join("outside/deploy"));
+        std::os::unix::fs::symlink(
+            dir.path().join("outside/deploy"),
+            scripts.join("proj/deploy"),
+        )
+        .unwrap();
+
+        let mut cache = ScriptCache::new(vec![scripts]);
+        assert_eq!(cache.resolve(&deploy("proj")), None);
+    }
+
+    #[test]
+    fn roots_are_searched_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (team, shared) = (dir.path().join("team"), dir.path().join("shared"));
+        executable(&team.join("proj/deploy"));
+        executable(&shared.join("proj/deploy"));
+        executable(&shared.join("site/deploy"));
+
+        let mut cache = ScriptCache::new(vec![team.clone(), shared.clone()]);
+        assert_eq!(
+            cache.resolve(&deploy("proj")),
+            Some(team.join("proj/deploy"))
+        );
+        assert_eq!(
+            cache.resolve(&deploy("site")),
+            Some(shared.join("site/deploy"))
+        );
+        assert_eq!(cache.resolve(&deploy("docs")), None);
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub struct Signals {
+    pub rescan_requested: Arc<AtomicBool>,
+    pub running: Arc<Running>,
+}
+
+/// Blocks the signals dispatch cares about and handles them on a dedicated
+/// thread. Must be called before any other threads are spawned so they all
+/// inherit the mask. SIGTERM and SIGINT stop new scripts from starting, give
+/// running ones up to `grace` to finish, then remove the pid file and exit.
+/// SIGUSR1 requests a rescan of the scripts directory.
+pub fn install(pid_file: Option<PathBuf>, grace: Duration) -> io::Result<Signals> {
+    let set = unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        set
+    };
+
+    let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+
+    let rescan = Arc::new(AtomicBool::new(false));
+    let rescan_requested = rescan.clone();
+    let running = Arc::new(Running::default());
+    let stopping = running.clone();
+
+    std::thread::spawn(move || loop {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&set, &mut signal) };
+
+        if signal == libc::SIGUSR1 {
+            tracing::info!("received SIGUSR1, rescanning scripts on next command");
+            rescan_requested.store(true, Ordering::Relaxed);
+            continue;
+        }
+
+        tracing::info!("received signal {signal}, shutting down");
+        if !stopping.stop(grace) {
+            tracing::warn!("scripts still running after {grace:?}, exiting anyway");
+        }
+        if let Some(path) = pid_file.as_ref() {
+            if let Err(err) = std::fs::remove_file(path) {
+                tracing::error!("unable to remove pid file {}: {err:?}", path.display());
+            }
+        }
+        std::process::exit(0);
+    });
+
+    Ok(Signals {
+        rescan_requested: rescan,
+        running,
+    })
+}
+
+/// Counts the scripts running, so shutdown can wait for them.
+#[derive(Default)]
+pub struct Running {
+    state: Mutex<RunningState>,
+    idle: Condvar,
+}
+
+#[derive(Default)]
+struct RunningState {
+    count: usize,
+    stopping: bool,
+}
+
+impl Running {
+    /// Counts a script as running until the guard is dropped. None once
+    /// shutdown has begun, the script must not be started.
+    pub fn start(self: &Arc<Self>) -> Option<RunningGuard> {
+        let mut state = self.state.lock().unwrap();
+        if state.stopping {
+            return None;
+        }
+        state.count += 1;
+        Some(RunningGuard(self.clone()))
+    }
+
+    /// Stops new scripts from starting and waits up to `grace` for running
+    /// ones to finish, false if some are still running.
+    fn stop(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        let mut state = self.state.lock().unwrap();
+        state.stopping = true;
+        if state.count > 0 {
+            tracing::info!("waiting for {} running scripts to finish", state.count);
+        }
+
+        while state.count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.idle.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+pub struct RunningGuard(Arc<Running>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.count -= 1;
+        self.0.idle.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_waits_for_the_running_script() {
+        let running = Arc::new(Running::default());
+        let guard = running.start().unwrap();
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let script = {
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                finished.store(true, Ordering::Relaxed);
+                drop(guard);
+            })
+        };
+
+        assert!(running.stop(Duration::from_secs(5)));
+        assert!(finished.load(Ordering::Relaxed), "stopped mid-script");
+        assert!(running.start().is_none(), "started a script after stopping");
+        script.join().unwrap();
+    }
+
+    #[test]
+    fn stop_gives_up_after_the_grace_period() {
+        let running = Arc::new(Running::default());
+        let _guard = running.start().unwrap();
+
+        let started = Instant::now();
+        assert!(!running.stop(Duration::from_millis(100)));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn stop_without_running_scripts_is_immediate() {
+        let running = Arc::new(Running::default());
+        drop(running.start().unwrap());
+        assert!(running.stop(Duration::ZERO));
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Exports spans over OTLP/HTTP to `endpoint`, the collector's full traces URL.
+/// Spans are exported as they close since there is no async runtime to batch on.
+pub fn layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, trace::Tracer>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_http_client(reqwest::blocking::Client::new())
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::Config::default()
+                .with_resource(Resource::new([KeyValue::new("service.name", "webhooks")])),
+        )
+        .install_simple()?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(provider.tracer("dispatch")))
+}
+
+/// Makes `span` a child of the server span that dispatched the command.
+pub fn set_parent(span: &tracing::Span, trace_context: &BTreeMap<String, String>) {
+    let context = TraceContextPropagator::new().extract(&Carrier(trace_context));
+    span.set_parent(context);
+}
+
+struct Carrier<'a>(&'a BTreeMap<String, String>);
+
+impl Extractor for Carrier<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+
+use config::{Config, ConfigFormat};
+
+/// One client, `abc`, allowed to deploy `proj`. `{dir}` is replaced with a
+/// directory the test owns.
+pub const CONFIG: &str = r#"
+version = 1
+
+[webhooks]
+pipe = "{dir}/pipe"
+listen_addr = "127.0.0.1"
+listen_port = 0
+
+[dispatch]
+pipe = "{dir}/pipe"
+scripts_dir = "{dir}/scripts"
+
+[clients.abc]
+secret = "s3cret"
+project = "proj"
+permissions = ["deploy"]
+"#;
+
+/// Parses and validates `toml` with `{dir}` replaced, leaked like dispatch's
+/// own config.
+pub fn config(toml: &str, dir: &Path) -> &'static Config {
+    let toml = toml.replace("{dir}", &dir.display().to_string());
+    let config = Config::parse(&toml, ConfigFormat::Toml).expect("test config parses");
+    config.validate().expect("test config is valid");
+    Box::leak(Box::new(config))
+}
+
+/// Makes a fifo at `path`.
+pub fn mkfifo(path: &Path) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+}
+
+/// Answers every request to the returned url with 200 and hands back each
+/// request's body.
+pub fn mock_server() -> (String, mpsc::Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = BufReader::new(stream.unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).unwrap();
+            stream
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .unwrap();
+            if tx.send(body).is_err() {
+                return;
+            }
+        }
+    });
+
+    (url, rx)
+}
+
+/// Collects everything a subscriber writes.
+#[derive(Clone, Default)]
+pub struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `f` with what it logs on this thread, debug and up, written to
+/// `captured`.
+pub fn capture_logs<T>(captured: &Captured, f: impl FnOnce() -> T) -> T {
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, f)
+}
+
+/// Logs from every thread of the test binary, for what is logged off the
+/// test's own thread. Tests share it, so should look for lines only they log.
+pub fn global_logs() -> &'static Captured {
+    static LOGS: OnceLock<Captured> = OnceLock::new();
+    LOGS.get_or_init(|| {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .init();
+        captured
+    })
+}
@@ -0,0 +1,196 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// The user and groups scripts are run as, looked up once at startup so a
+/// typo fails then rather than on the first deploy.
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+impl RunAs {
+    /// Users and groups may be given as names or numeric ids, either way they
+    /// must exist. The group defaults to the user's primary group.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> io::Result<Option<RunAs>> {
+        let user = user.map(lookup_user).transpose()?;
+        let group = group.map(lookup_group).transpose()?;
+
+        let run_as = match (user, group) {
+            (None, None) => return Ok(None),
+            (Some(user), group) => {
+                let gid = group.unwrap_or(user.gid);
+                RunAs {
+                    uid: Some(user.uid),
+                    gid,
+                    groups: group_list(&user.name, gid)?,
+                }
+            }
+            (None, Some(gid)) => RunAs {
+                uid: None,
+                gid,
+                groups: vec![gid],
+            },
+        };
+        Ok(Some(run_as))
+    }
+
+    /// Drops to the user in the child just before exec. This is done by hand
+    /// rather than with `CommandExt::uid` and `gid`, which switch user before
+    /// any `pre_exec` hook runs and leave no privilege to set the
+    /// supplementary groups with.
+    pub fn apply(&self, command: &mut Command) {
+        let run_as = self.clone();
+        // only async-signal-safe calls on memory allocated before the fork
+        unsafe {
+            command.pre_exec(move || run_as.switch());
+        }
+    }
+
+    fn switch(&self) -> io::Result<()> {
+        if unsafe { libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+struct User {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+fn lookup_user(user: &str) -> io::Result<User> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    let err = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+        Err(_) => {
+            let name = c_string(user)?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    if found.is_null() {
+        return Err(not_found(format!("no such user: {user}")));
+    }
+
+    Ok(User {
+        name: unsafe { CStr::from_ptr(passwd.pw_name) }.to_owned(),
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+    })
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    let err = match group.parse::<libc::gid_t>() {
+        Ok(gid) => unsafe {
+            libc::getgrgid_r(gid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+        Err(_) => {
+            let name = c_string(group)?;
+            unsafe {
+                libc::getgrnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    if found.is_null() {
+        return Err(not_found(format!("no such group: {group}")));
+    }
+
+    Ok(entry.gr_gid)
+}
+
+/// `gid` and every group `user` is a member of.
+fn group_list(user: &CStr, gid: libc::gid_t) -> io::Result<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut len = groups.len() as libc::c_int;
+        let found =
+            unsafe { libc::getgrouplist(user.as_ptr(), gid, groups.as_mut_ptr(), &mut len) };
+        if found >= 0 {
+            groups.truncate(len as usize);
+            return Ok(groups);
+        }
+        // too small, len now holds how many are needed
+        groups.resize((len as usize).max(groups.len() * 2), 0);
+    }
+}
+
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| not_found(format!("invalid name: {name}")))
+}
+
+fn not_found(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_user_fails_to_resolve() {
+        let err = RunAs::resolve(Some("no-such-user-here"), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(RunAs::resolve(None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn script_runs_as_the_configured_user() {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skipping, switching user needs root");
+            return;
+        }
+
+        let run_as = RunAs::resolve(Some("nobody"), None).unwrap().unwrap();
+        let mut command = Command::new("id");
+        command.arg("-u");
+        run_as.apply(&mut command);
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+
+        let uid = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(uid.trim(), run_as.uid.unwrap().to_string());
+        assert_ne!(uid.trim(), "0");
+    }
+}
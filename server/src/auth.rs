@@ -0,0 +1,879 @@
+use axum::{
+    body::{boxed, Body, Full},
+    extract::{ConnectInfo, FromRequest, RequestParts},
+    http::{self, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use config::{ClientConfig, Config, Provider, SignatureAlgorithm};
+
+use crate::body::{self, BodyError};
+use crate::dispatcher::{self, Dispatched};
+use crate::error::ApiError;
+use crate::metrics::METRICS;
+use crate::rate_limit::RateLimits;
+use crate::replay::SeenDeliveries;
+use crate::{signature, tls};
+
+const RESPONSE_SIGNATURE_HEADER: http::header::HeaderName =
+    http::header::HeaderName::from_static("x-webhook-response-signature");
+const FORWARDED_FOR_HEADER: http::header::HeaderName =
+    http::header::HeaderName::from_static("x-forwarded-for");
+pub const GITHUB_EVENT_HEADER: http::header::HeaderName =
+    http::header::HeaderName::from_static("x-github-event");
+pub const GITHUB_DELIVERY_HEADER: http::header::HeaderName =
+    http::header::HeaderName::from_static("x-github-delivery");
+
+#[derive(Debug, Copy, Clone)]
+pub struct Authed<'a>(pub &'a ClientConfig);
+
+/// The outcome of authenticating a request, decided once by
+/// `validate_signature` and stored in the request extensions for anything
+/// downstream that needs to know how the request fared.
+#[derive(Debug, Copy, Clone)]
+pub enum AuthDecision {
+    Authed(Authed<'static>),
+    MissingHeader,
+    UnknownClient,
+    BadSignature,
+    Forbidden,
+    AddressNotAllowed,
+    /// Named a client and passed its certificate and address checks, then
+    /// was answered from its headers alone without the signature being
+    /// checked. See `header_filter`.
+    Unverified(Authed<'static>, HeaderFilter),
+}
+
+/// Why a request was answered before its body was read.
+#[derive(Debug, Copy, Clone)]
+pub enum HeaderFilter {
+    IgnoredEvent,
+    SeenDelivery,
+}
+
+impl HeaderFilter {
+    fn response(self) -> Response {
+        match self {
+            HeaderFilter::IgnoredEvent => Dispatched::Ignored.into_response(),
+            HeaderFilter::SeenDelivery => Dispatched::Duplicate.into_response(),
+        }
+    }
+}
+
+impl AuthDecision {
+    pub fn rejection(&self) -> Option<ApiError> {
+        match self {
+            AuthDecision::Authed(_) | AuthDecision::Unverified(..) => None,
+            AuthDecision::MissingHeader
+            | AuthDecision::UnknownClient
+            | AuthDecision::BadSignature => Some(ApiError::unauthorized()),
+            AuthDecision::Forbidden => Some(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "client certificate does not match",
+            )),
+            AuthDecision::AddressNotAllowed => Some(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "address_not_allowed",
+                "request did not come from an address allowed for the client",
+            )),
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn validate_signature(req: Request<Body>, next: Next<Body>) -> Response {
+    METRICS.request_received();
+    let config = req.extensions().get::<&'static Config>().cloned();
+    let reject_unauthenticated = config.is_none_or(|c| c.webhooks.reject_unauthenticated);
+
+    let (mut req, decision) = match authenticate(req, config).await {
+        Ok(authenticated) => authenticated,
+        Err(res) => return res,
+    };
+
+    if let AuthDecision::Unverified(Authed(client), filter) = decision {
+        tracing::info!(
+            "answering request for {} without checking its signature: {filter:?}",
+            client.display_name()
+        );
+        METRICS.unverified();
+        return with_decision(filter.response(), decision);
+    }
+    METRICS.authenticated(matches!(decision, AuthDecision::Authed(_)));
+
+    match decision {
+        AuthDecision::Authed(Authed(client)) => {
+            tracing::info!(
+                "webhook request authenticated for {}",
+                client.display_name()
+            );
+            if client.provider == Provider::GitHub
+                && client.require_github_event_header
+                && !req.headers().contains_key(&GITHUB_EVENT_HEADER)
+            {
+                tracing::info!(
+                    "webhook request for {} missing github event header",
+                    client.display_name()
+                );
+                let rejection = ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "missing_event_header",
+                    "request is missing the X-GitHub-Event header",
+                );
+                return with_decision(rejection.into_response(), decision);
+            }
+            if let Some(rejection) = rate_limited(&req, client) {
+                return with_decision(rejection.into_response(), decision);
+            }
+        }
+        decision => {
+            if let AuthDecision::MissingHeader = decision {
+                tracing::info!("webhook request missing required headers");
+            } else {
+                tracing::info!("webhook request unable to be authenticated: {decision:?}");
+            }
+            if let Some(rejection) = decision.rejection().filter(|_| reject_unauthenticated) {
+                return rejection.into_response();
+            }
+        }
+    }
+
+    let delivery = match decision {
+        AuthDecision::Authed(_) => first_delivery(&req, config),
+        _ => Delivery::Unchecked,
+    };
+    if let Delivery::Duplicate(id) = &delivery {
+        tracing::info!("ignoring duplicate delivery {id}");
+    }
+
+    req.extensions_mut().insert(decision);
+    let deliveries = req.extensions().get::<Arc<SeenDeliveries>>().cloned();
+    let res = match delivery {
+        Delivery::Duplicate(_) => Dispatched::Duplicate.into_response(),
+        _ => next.run(req).await,
+    };
+
+    if let (Delivery::First(id), Some(deliveries)) = (&delivery, deliveries) {
+        if res.status().is_server_error() {
+            deliveries.forget(id);
+        }
+    }
+
+    let res = match decision {
+        AuthDecision::Authed(Authed(client)) if client.sign_responses => {
+            sign_response(client, res).await
+        }
+        _ => res,
+    };
+    with_decision(res, decision)
+}
+
+/// Lets middleware further out, like the delivery history, see who the
+/// request was from.
+fn with_decision(mut res: Response, decision: AuthDecision) -> Response {
+    res.extensions_mut().insert(decision);
+    res
+}
+
+fn rate_limited(req: &Request<Body>, client: &ClientConfig) -> Option<ApiError> {
+    let per_minute = client.rate_per_minute?;
+    let limits = req.extensions().get::<Arc<RateLimits>>()?;
+    let burst = client.burst.unwrap_or(per_minute.get());
+
+    let retry_after = limits.acquire(&client.name, per_minute, burst).err()?;
+    tracing::info!("rate limiting requests from {}", client.display_name());
+    Some(
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "too many requests from this client",
+        )
+        .with_retry_after(retry_after.as_secs_f64().ceil() as u64),
+    )
+}
+
+enum Delivery {
+    Unchecked,
+    First(String),
+    Duplicate(String),
+}
+
+/// Whether the headers alone show the request could never dispatch, an
+/// ignored event or a delivery already taken, so it can be answered before
+/// its body is read. Checking the signature needs the whole body, so this is
+/// what spares a large payload from being buffered. The cost is that these
+/// requests are answered without being verified, which is only safe because
+/// they dispatch nothing: a ping signed with the wrong secret is ignored
+/// rather than rejected, and a delivery id is only ever recorded once its
+/// signature has matched. Only requests carrying a signature header and
+/// passing the client's certificate and address checks get this far.
+fn header_filter(
+    req: &Request<Body>,
+    client: &ClientConfig,
+    config: Option<&'static Config>,
+) -> Option<HeaderFilter> {
+    if dispatcher::ignores_event(client, req.headers()) {
+        return Some(HeaderFilter::IgnoredEvent);
+    }
+
+    let (deliveries, config) = match (req.extensions().get::<Arc<SeenDeliveries>>(), config) {
+        (Some(deliveries), Some(config)) if config.webhooks.replay_cache_size > 0 => {
+            (deliveries, config)
+        }
+        _ => return None,
+    };
+    let id = req
+        .headers()
+        .get(&GITHUB_DELIVERY_HEADER)
+        .and_then(|v| v.to_str().ok())?;
+    let ttl = Duration::from_secs(config.webhooks.replay_ttl_secs);
+    if deliveries.contains(id, ttl) {
+        tracing::info!("ignoring duplicate delivery {id}");
+        return Some(HeaderFilter::SeenDelivery);
+    }
+    None
+}
+
+fn first_delivery(req: &Request<Body>, config: Option<&'static Config>) -> Delivery {
+    let (deliveries, config) = match (req.extensions().get::<Arc<SeenDeliveries>>(), config) {
+        (Some(deliveries), Some(config)) => (deliveries, config),
+        _ => return Delivery::Unchecked,
+    };
+
+    let id = match req
+        .headers()
+        .get(&GITHUB_DELIVERY_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(id) => id.to_string(),
+        None => {
+            tracing::info!("webhook request has no delivery id, replays cannot be detected");
+            return Delivery::Unchecked;
+        }
+    };
+
+    let ttl = Duration::from_secs(config.webhooks.replay_ttl_secs);
+    if deliveries.insert(&id, config.webhooks.replay_cache_size, ttl) {
+        Delivery::First(id)
+    } else {
+        Delivery::Duplicate(id)
+    }
+}
+
+async fn sign_response(client: &ClientConfig, res: Response) -> Response {
+    let (mut parts, body) = res.into_parts();
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            tracing::warn!("unable to read response body to sign: {err}");
+            return ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "response_failed",
+                "unable to sign response",
+            )
+            .into_response();
+        }
+    };
+
+    // echoing a token back would hand out the secret, sign with an hmac instead
+    let algorithm = match client.signature_algorithm {
+        SignatureAlgorithm::GitlabToken => SignatureAlgorithm::Sha256,
+        algorithm => algorithm,
+    };
+    let signature = signature::expected_signature(algorithm, &client.secret, &bytes);
+    if let Ok(value) = HeaderValue::from_str(&signature) {
+        parts.headers.insert(RESPONSE_SIGNATURE_HEADER, value);
+    }
+
+    Response::from_parts(parts, boxed(Full::from(bytes)))
+}
+
+/// Reads the body to check the signature, handing back the request with the
+/// body restored, unless `header_filter` shows there is no need to. Only a
+/// body too large to read is answered immediately.
+async fn authenticate(
+    req: Request<Body>,
+    config: Option<&'static Config>,
+) -> Result<(Request<Body>, AuthDecision), Response> {
+    for (name, value) in req.headers().iter() {
+        tracing::trace!("Header: {}={}", name.as_str(), value.to_str().unwrap_or(""));
+    }
+
+    let client_key = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(client_key);
+
+    let client_key = match client_key {
+        Some(client_key) => client_key,
+        None => return Ok((req, AuthDecision::MissingHeader)),
+    };
+
+    let client = config.and_then(|config| lookup_client(config, &client_key));
+
+    let client = match client {
+        Some(client) => client,
+        None => return Ok((req, AuthDecision::UnknownClient)),
+    };
+
+    let signature_header = client.signature_header();
+    let has_sig = req.headers().contains_key(signature_header);
+
+    if has_sig && peer_matches(&req, client) && address_allowed(&req, client, config).is_ok() {
+        if let Some(filter) = header_filter(&req, client, config) {
+            return Ok((req, AuthDecision::Unverified(Authed(client), filter)));
+        }
+    }
+
+    let max_body_bytes = client
+        .max_body_bytes
+        .or(config.map(|c| c.webhooks.max_body_bytes))
+        .unwrap_or(u64::MAX);
+
+    if body::content_length_exceeds(req.headers(), max_body_bytes) {
+        tracing::info!("webhook request body exceeds {max_body_bytes} bytes");
+        return Err(ApiError::payload_too_large().into_response());
+    }
+
+    let (parts, body) = req.into_parts();
+
+    // the header is the fast path, trailers are only waited on when a streaming
+    // sender could not provide the signature up front
+    let (bytes, trailers) = match body::read_limited(body, max_body_bytes, !has_sig).await {
+        Ok(read) => read,
+        Err(BodyError::TooLarge) => {
+            tracing::info!("webhook request body exceeds {max_body_bytes} bytes");
+            return Err(ApiError::payload_too_large().into_response());
+        }
+        Err(BodyError::Read(_err)) => {
+            tracing::warn!("unable to read webhook body");
+            let req = Request::from_parts(parts, Body::empty());
+            return Ok((req, AuthDecision::BadSignature));
+        }
+    };
+
+    tracing::trace!("read body, got {} bytes", bytes.len());
+    METRICS.body_read(bytes.len());
+    tracing::trace!("{}", String::from_utf8_lossy(&bytes));
+
+    let req = Request::from_parts(parts, Body::from(bytes.clone()));
+
+    let captured_trailers = req.extensions().get::<body::Trailers>().map(|t| &t.0);
+    let signature = req
+        .headers()
+        .get(signature_header)
+        .or_else(|| trailers.as_ref()?.get(signature_header))
+        .or_else(|| captured_trailers?.get(signature_header))
+        .and_then(|s| s.to_str().ok())
+        .map(String::from);
+
+    // a token is the secret itself, never log it
+    if client.signature_algorithm != SignatureAlgorithm::GitlabToken {
+        tracing::trace!(
+            "expected signature: {}",
+            signature::expected_signature(client.signature_algorithm, &client.secret, &bytes)
+        );
+        if let Some(sig) = signature.as_ref() {
+            tracing::trace!("provided signature: {}", sig);
+        }
+    }
+    if signature.is_none() {
+        tracing::trace!("no signature provided");
+    }
+
+    if !peer_matches(&req, client) {
+        tracing::info!("webhook request client certificate does not match");
+        return Ok((req, AuthDecision::Forbidden));
+    }
+
+    let signature_matches = signature.as_deref().is_some_and(|sig| {
+        signature::matches(client.signature_algorithm, &client.secret, &bytes, sig)
+    });
+    if signature_matches {
+        if let Err(addr) = address_allowed(&req, client, config) {
+            tracing::info!(
+                "webhook request for {} from disallowed address {addr:?}",
+                client.display_name()
+            );
+            return Ok((req, AuthDecision::AddressNotAllowed));
+        }
+        return Ok((req, AuthDecision::Authed(Authed(client))));
+    }
+
+    if let Some(sig) = signature.as_deref() {
+        if config.is_some_and(|c| c.webhooks.signature_diagnostics) {
+            match signature::diagnose_mismatch(
+                client.signature_algorithm,
+                &client.secret,
+                &bytes,
+                sig,
+            ) {
+                Some(variant) => tracing::warn!(
+                    "signature for {} would match with {variant}, body was likely altered in transit",
+                    client.display_name()
+                ),
+                None => tracing::info!("no normalized body matches the provided signature"),
+            }
+        }
+    }
+
+    Ok((req, AuthDecision::BadSignature))
+}
+
+fn peer_matches(req: &Request<Body>, client: &ClientConfig) -> bool {
+    let peer_names = req.extensions().get::<tls::PeerNames>();
+    match client.tls_client_name.as_deref() {
+        Some(name) => peer_names.is_some_and(|names| names.contains(name)),
+        None => true,
+    }
+}
+
+/// Fails with the address the request came from, if known, when the client
+/// is limited to `allowed_cidrs` that don't include it.
+fn address_allowed(
+    req: &Request<Body>,
+    client: &ClientConfig,
+    config: Option<&'static Config>,
+) -> Result<(), Option<IpAddr>> {
+    if client.allowed_cidrs.is_empty() {
+        return Ok(());
+    }
+    let trusted_proxies = config.map_or(&[][..], |c| &c.webhooks.trusted_proxies);
+    let addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| client_addr(peer.ip(), req.headers(), trusted_proxies));
+    match addr {
+        Some(addr) if client.allowed_cidrs.iter().any(|net| net.contains(&addr)) => Ok(()),
+        addr => Err(addr),
+    }
+}
+
+/// The client the request's Authorization header names, whether or not the
+/// request goes on to authenticate as it.
+pub fn named_client(headers: &HeaderMap, config: &'static Config) -> Option<&'static ClientConfig> {
+    let client_key = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(client_key)?;
+    lookup_client(config, &client_key)
+}
+
+fn lookup_client(config: &'static Config, client_key: &str) -> Option<&'static ClientConfig> {
+    let client_key = client_key.strip_suffix(':').unwrap_or(client_key);
+    config.clients.get(client_key)
+}
+
+/// Basic credentials carry the client key as the username, a bearer token is
+/// the client key itself.
+fn client_key(authorization: &str) -> Option<String> {
+    if let Some(credentials) = authorization.strip_prefix("Basic ") {
+        tracing::trace!("authorization scheme: basic");
+        let key = base64::decode(credentials.as_bytes()).ok()?;
+        return String::from_utf8(key).ok();
+    }
+
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        tracing::trace!("authorization scheme: bearer");
+        return Some(token.trim().to_string());
+    }
+
+    None
+}
+
+/// The peer, unless it is a trusted proxy, in which case the nearest
+/// `X-Forwarded-For` hop that is not also a trusted proxy.
+fn client_addr(peer: IpAddr, headers: &http::HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let trusted = |addr: &IpAddr| trusted_proxies.iter().any(|net| net.contains(addr));
+    if !trusted(&peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|addr| !trusted(addr))
+        .unwrap_or(peer)
+}
+
+#[async_trait::async_trait]
+impl<B: Send> FromRequest<B> for Authed<'_> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        match req.extensions().get::<AuthDecision>() {
+            Some(AuthDecision::Authed(authed)) => Ok(*authed),
+            Some(decision) => Err(decision.rejection().unwrap_or_else(ApiError::unauthorized)),
+            None => Err(ApiError::unauthorized()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    /// A body that fails if anything reads it, so a request answered with it
+    /// can't have had its signature checked.
+    fn unreadable_body() -> Body {
+        let (sender, body) = Body::channel();
+        sender.abort();
+        body
+    }
+
+    fn event(event: &str, signature: Option<&str>) -> Request<Body> {
+        let mut req = Request::post("/deploy")
+            .header("Authorization", "Bearer abc")
+            .header("X-GitHub-Event", event);
+        if let Some(signature) = signature {
+            req = req.header("X-Hub-Signature-256", signature);
+        }
+        req.body(unreadable_body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ignored_event_is_answered_without_reading_the_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let res = app.oneshot(event("ping", Some("sha256=00"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn dispatched_event_reads_the_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let res = app.oneshot(event("push", Some("sha256=00"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn ignored_event_without_a_signature_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let res = app.oneshot(event("ping", None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn ignored_event_from_a_disallowed_address_is_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("{CONFIG}allowed_cidrs = [\"10.0.0.0/8\"]\n");
+        let app = test_support::app(&toml, dir.path());
+
+        let mut req = event("ping", Some("sha256=00"));
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([192, 0, 2, 1], 443))));
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn signed_request_is_dispatched() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        let res = app
+            .oneshot(test_support::signed("/deploy", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let line = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        let command = config::Command::from_wire(&line).unwrap();
+        assert_eq!(command.to_string(), "deploy proj");
+    }
+
+    #[tokio::test]
+    async fn missing_signature_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let mut req = test_support::signed("/deploy", "{}");
+        req.headers_mut().remove("X-Hub-Signature-256");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn bad_signature_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let mut req = test_support::signed("/deploy", "{}");
+        *req.body_mut() = Body::from("{\"changed\": true}");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn client_key_from_either_scheme() {
+        assert_eq!(client_key("Bearer abc").as_deref(), Some("abc"));
+        let basic = format!("Basic {}", base64::encode("abc:"));
+        assert_eq!(client_key(&basic).as_deref(), Some("abc:"));
+        assert_eq!(client_key("Digest abc"), None);
+    }
+
+    #[tokio::test]
+    async fn bearer_token_for_no_client_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let mut req = test_support::signed("/deploy", "{}");
+        req.headers_mut()
+            .insert("Authorization", "Bearer xyz".parse().unwrap());
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn signature_is_read_from_the_configured_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let toml =
+            format!("{CONFIG}signature_algorithm = \"sha1\"\nsignature_header = \"X-Sig\"\n");
+        let app = test_support::app(&toml, dir.path());
+
+        let signature = signature::expected_signature(SignatureAlgorithm::Sha1, "s3cret", b"{}");
+        let signed = |header: &str| {
+            Request::post("/deploy")
+                .header("Authorization", "Bearer abc")
+                .header(header, &signature)
+                .body(Body::from("{}"))
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(signed("X-Hub-Signature"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = app.oneshot(signed("X-Sig")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn gitlab_token_header_authenticates() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let toml = format!("{CONFIG}signature_algorithm = \"gitlab_token\"\n");
+        let app = test_support::app(&toml, dir.path());
+
+        let req = Request::post("/deploy")
+            .header("Authorization", "Bearer abc")
+            .header("X-Gitlab-Token", "s3cret")
+            .body(Body::from("{}"))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn burst_is_allowed_then_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let toml = format!("{CONFIG}rate_per_minute = 1\nburst = 2\n");
+        let app = test_support::app(&toml, dir.path());
+
+        for _ in 0..2 {
+            let res = app
+                .clone()
+                .oneshot(test_support::signed("/deploy", "{}"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+
+        let res = app
+            .oneshot(test_support::signed("/deploy", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = res.headers()["Retry-After"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after), "{retry_after}");
+    }
+
+    /// A signed request from `peer`, forwarded for `forwarded_for` if given.
+    fn signed_from(peer: [u8; 4], forwarded_for: Option<&str>) -> Request<Body> {
+        let mut req = test_support::signed("/deploy", "{}");
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 443))));
+        if let Some(forwarded_for) = forwarded_for {
+            req.headers_mut()
+                .insert(FORWARDED_FOR_HEADER, forwarded_for.parse().unwrap());
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn only_allowed_addresses_are_dispatched() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let toml = format!("{CONFIG}allowed_cidrs = [\"10.0.0.0/8\"]\n");
+        let app = test_support::app(&toml, dir.path());
+
+        let res = app
+            .clone()
+            .oneshot(signed_from([10, 1, 2, 3], None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let res = app
+            .oneshot(signed_from([192, 0, 2, 1], None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(lines.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_is_only_honored_from_a_trusted_proxy() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("{CONFIG}allowed_cidrs = [\"10.0.0.0/8\"]\n");
+        let app = test_support::app(&toml, dir.path());
+
+        let res = app
+            .oneshot(signed_from([192, 0, 2, 1], Some("10.1.2.3")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let _lines = test_support::pipe_reader(dir.path());
+        let toml = toml.replace(
+            "[dispatch]",
+            "trusted_proxies = [\"192.0.2.0/24\"]\n\n[dispatch]",
+        );
+        let app = test_support::app(&toml, dir.path());
+        let res = app
+            .oneshot(signed_from([192, 0, 2, 1], Some("10.1.2.3")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    const BODY: &str = r#"{"ref": "refs/heads/main"}"#;
+
+    /// `BODY` signed by `abc`, its length declared up front or streamed in
+    /// chunks with no Content-Length.
+    fn sized(declared: bool) -> Request<Body> {
+        let mut req = test_support::signed("/deploy", BODY);
+        if declared {
+            req.headers_mut()
+                .insert(http::header::CONTENT_LENGTH, BODY.len().into());
+        } else {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for chunk in BODY.as_bytes().chunks(4) {
+                    let chunk = axum::body::Bytes::copy_from_slice(chunk);
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            *req.body_mut() = body;
+        }
+        req
+    }
+
+    async fn assert_too_large(res: Response) {
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "payload_too_large",
+                "message": "request body exceeds the maximum allowed size",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn body_one_byte_over_the_limit_is_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let limit = format!("max_body_bytes = {}\n\n[dispatch]", BODY.len() - 1);
+        let app = test_support::app(&CONFIG.replace("[dispatch]", &limit), dir.path());
+
+        for declared in [true, false] {
+            let res = app.clone().oneshot(sized(declared)).await.unwrap();
+            assert_too_large(res).await;
+        }
+        assert!(lines.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // exactly at the limit is fine
+        let limit = format!("max_body_bytes = {}\n\n[dispatch]", BODY.len());
+        let app = test_support::app(&CONFIG.replace("[dispatch]", &limit), dir.path());
+        for declared in [true, false] {
+            let res = app.clone().oneshot(sized(declared)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+    }
+
+    /// `BODY` is 26 bytes.
+    fn limits(global: usize, client: usize) -> String {
+        let global = format!("max_body_bytes = {global}\n\n[dispatch]");
+        format!(
+            "{}max_body_bytes = {client}\n",
+            CONFIG.replace("[dispatch]", &global)
+        )
+    }
+
+    #[tokio::test]
+    async fn larger_client_limit_accepts_a_body_over_the_global_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(&limits(16, 64), dir.path());
+
+        for declared in [true, false] {
+            let res = app.clone().oneshot(sized(declared)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn smaller_client_limit_rejects_a_body_under_the_global_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(&limits(64, 16), dir.path());
+
+        for declared in [true, false] {
+            let res = app.clone().oneshot(sized(declared)).await.unwrap();
+            assert_too_large(res).await;
+        }
+        assert!(lines.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{self, HeaderMap},
+};
+use hyper::body::HttpBody;
+
+/// Trailers consumed by an earlier middleware while buffering the body, kept in
+/// the request extensions since the rebuilt body cannot carry them.
+#[derive(Debug, Clone)]
+pub struct Trailers(pub HeaderMap);
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    Read(hyper::Error),
+}
+
+/// Buffers the body, giving up as soon as it grows past `limit` bytes rather
+/// than after it has all been read.
+pub async fn read_limited(
+    mut body: Body,
+    limit: u64,
+    with_trailers: bool,
+) -> Result<(Bytes, Option<HeaderMap>), BodyError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(BodyError::Read)?;
+        if (bytes.len() + chunk.len()) as u64 > limit {
+            return Err(BodyError::TooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let trailers = if with_trailers {
+        body.trailers().await.map_err(BodyError::Read)?
+    } else {
+        None
+    };
+
+    Ok((bytes.into(), trailers))
+}
+
+pub fn content_length_exceeds(headers: &HeaderMap, limit: u64) -> bool {
+    headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|len| len > limit)
+}
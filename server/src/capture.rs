@@ -0,0 +1,247 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::Body,
+    http::{self, Request},
+    middleware::Next,
+    response::IntoResponse,
+};
+
+use config::{ClientConfig, Config, SignatureAlgorithm};
+
+use crate::auth;
+use crate::body::{self, BodyError};
+use crate::error::ApiError;
+
+const REDACTED_HEADERS: &[http::header::HeaderName] = &[
+    http::header::AUTHORIZATION,
+    http::header::PROXY_AUTHORIZATION,
+];
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+pub async fn capture_request(req: Request<Body>, next: Next<Body>) -> impl IntoResponse {
+    let config = req.extensions().get::<&'static Config>().cloned();
+
+    let capture_dir = match config.and_then(|c| c.webhooks.capture_dir.as_ref()) {
+        Some(dir) => dir,
+        None => return next.run(req).await,
+    };
+    let client = config.and_then(|c| auth::named_client(req.headers(), c));
+    let max_files = config.map(|c| c.webhooks.capture_max_files).unwrap_or(0);
+    let max_body_bytes = client
+        .and_then(|client| client.max_body_bytes)
+        .or(config.map(|c| c.webhooks.max_body_bytes))
+        .unwrap_or(u64::MAX);
+
+    if body::content_length_exceeds(req.headers(), max_body_bytes) {
+        return ApiError::payload_too_large().into_response();
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let (bytes, trailers) = match body::read_limited(body, max_body_bytes, true).await {
+        Ok(read) => read,
+        Err(BodyError::TooLarge) => return ApiError::payload_too_large().into_response(),
+        Err(BodyError::Read(_err)) => {
+            tracing::warn!("unable to read webhook body for capture");
+            let req = Request::from_parts(parts, Body::empty());
+            return next.run(req).await;
+        }
+    };
+
+    let mut capture = format!(
+        "{} {} {:?}\r\n",
+        parts.method,
+        parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/"),
+        parts.version
+    )
+    .into_bytes();
+    // trailers are recorded as headers so a replay still carries them
+    for (name, value) in parts.headers.iter().chain(trailers.iter().flatten()) {
+        capture.extend_from_slice(name.as_str().as_bytes());
+        capture.extend_from_slice(b": ");
+        if is_redacted(name, client, config) {
+            capture.extend_from_slice(b"[redacted]");
+        } else {
+            capture.extend_from_slice(value.as_bytes());
+        }
+        capture.extend_from_slice(b"\r\n");
+    }
+    capture.extend_from_slice(b"\r\n");
+    capture.extend_from_slice(&bytes);
+
+    if let Err(err) = write_capture(capture_dir, &capture, max_files).await {
+        tracing::warn!("unable to capture webhook request: {err:?}");
+    }
+
+    let mut req = Request::from_parts(parts, bytes.into());
+    if let Some(trailers) = trailers {
+        req.extensions_mut().insert(body::Trailers(trailers));
+    }
+    next.run(req).await
+}
+
+/// A token header carries the client's secret itself, so it is redacted for
+/// any client that authenticates with one, as is whatever header the named
+/// client signs with.
+fn is_redacted(
+    name: &http::header::HeaderName,
+    client: Option<&ClientConfig>,
+    config: Option<&Config>,
+) -> bool {
+    let is_signature_header = |client: &ClientConfig| {
+        name.as_str()
+            .eq_ignore_ascii_case(client.signature_header())
+    };
+    REDACTED_HEADERS.contains(name)
+        || name.as_str() == SignatureAlgorithm::GitlabToken.default_header()
+        || client.is_some_and(is_signature_header)
+        || config.is_some_and(|config| {
+            config.clients.values().any(|client| {
+                client.signature_algorithm == SignatureAlgorithm::GitlabToken
+                    && is_signature_header(client)
+            })
+        })
+}
+
+async fn write_capture(dir: &Path, capture: &[u8], max_files: usize) -> std::io::Result<()> {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    let path = dir.join(format!("{millis:020}-{seq:06}.http"));
+    tokio::fs::write(&path, capture).await?;
+    tracing::debug!("captured webhook request to: {}", path.display());
+
+    rotate(dir, max_files).await
+}
+
+async fn rotate(dir: &Path, max_files: usize) -> std::io::Result<()> {
+    let mut captures = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "http") {
+            captures.push(path);
+        }
+    }
+
+    if captures.len() <= max_files {
+        return Ok(());
+    }
+
+    captures.sort();
+    let excess = captures.len() - max_files;
+    for path in captures.into_iter().take(excess) {
+        tokio::fs::remove_file(&path).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Extension, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    const TOKEN_CLIENT: &str = r#"
+[clients.lab]
+secret = "t0ken"
+project = "other"
+permissions = ["deploy"]
+signature_algorithm = "gitlab_token"
+signature_header = "X-Lab-Token"
+"#;
+
+    async fn captured(config: &'static Config, req: Request<Body>) -> String {
+        let app = Router::new()
+            .route("/deploy", post(|| async {}))
+            .layer(axum::middleware::from_fn(capture_request))
+            .layer(Extension(config));
+        app.oneshot(req).await.unwrap();
+
+        let dir = config.webhooks.capture_dir.as_ref().unwrap();
+        let mut entries = std::fs::read_dir(dir).unwrap().flatten();
+        let capture = entries.next().expect("request was captured").path();
+        std::fs::read_to_string(capture).unwrap()
+    }
+
+    /// The config with captures kept in `<dir>/captures`.
+    fn capturing(toml: &str, dir: &Path) -> &'static Config {
+        let captures = dir.join("captures");
+        std::fs::create_dir(&captures).unwrap();
+        let toml = toml.replace(
+            "[dispatch]",
+            &format!("capture_dir = \"{}\"\n\n[dispatch]", captures.display()),
+        );
+        test_support::config(&toml, dir)
+    }
+
+    #[tokio::test]
+    async fn token_headers_are_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = capturing(&(CONFIG.to_string() + TOKEN_CLIENT), dir.path());
+
+        let req = Request::post("/deploy")
+            .header("Authorization", "Bearer lab")
+            .header("X-Lab-Token", "t0ken")
+            .header("X-Gitlab-Token", "t0ken")
+            .header("X-Hub-Signature-256", "sha256=abcdef")
+            .body(Body::from("{}"))
+            .unwrap();
+        let capture = captured(config, req).await;
+
+        assert!(!capture.contains("t0ken"), "{capture}");
+        assert!(capture.contains("x-lab-token: [redacted]"));
+        assert!(capture.contains("x-gitlab-token: [redacted]"));
+        assert!(capture.contains("authorization: [redacted]"));
+        // an hmac is not the secret, and is kept for replays
+        assert!(capture.contains("x-hub-signature-256: sha256=abcdef"));
+    }
+
+    #[tokio::test]
+    async fn client_body_limit_overrides_the_global_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "max_body_bytes = 4\n\n[dispatch]")
+            + "max_body_bytes = 16\n";
+        let config = capturing(&toml, dir.path());
+
+        let req = Request::post("/deploy")
+            .header("Authorization", "Bearer abc")
+            .body(Body::from("{\"large\": 1}"))
+            .unwrap();
+        let capture = captured(config, req).await;
+        assert!(capture.ends_with("{\"large\": 1}"), "{capture}");
+    }
+
+    #[tokio::test]
+    async fn global_body_limit_applies_without_a_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "max_body_bytes = 4\n\n[dispatch]")
+            + "max_body_bytes = 16\n";
+        let config = capturing(&toml, dir.path());
+
+        let app = Router::new()
+            .route("/deploy", post(|| async {}))
+            .layer(axum::middleware::from_fn(capture_request))
+            .layer(Extension(config));
+        let req = Request::post("/deploy")
+            .header("Authorization", "Bearer unknown")
+            .body(Body::from("{\"large\": 1}"))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
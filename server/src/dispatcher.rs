@@ -0,0 +1,1701 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    http::{self, header::HeaderName, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    sync::{mpsc, oneshot},
+    time::{timeout, timeout_at},
+};
+
+use config::journal::Journal;
+use config::throttle::ThrottledLog;
+use config::{
+    Action, ClientConfig, CommandResult, Config, DispatchConfig, Transport, REPO_PLACEHOLDER,
+};
+
+use crate::auth::{Authed, GITHUB_DELIVERY_HEADER, GITHUB_EVENT_HEADER};
+use crate::clock::{Clock, SystemClock};
+use crate::history::Outcome;
+use crate::metrics::METRICS;
+use crate::request_id::REQUEST_ID_HEADER;
+
+const SINK_FAILURE_THRESHOLD: u32 = 3;
+const SINK_COOLDOWN: Duration = Duration::from_secs(30);
+
+const PROJECT_HEADER: HeaderName = HeaderName::from_static("x-deploy-project");
+
+static SINK_ERRORS: ThrottledLog = ThrottledLog::new(Duration::from_secs(10));
+
+pub struct Dispatcher {
+    sinks: Vec<Sink>,
+    current_weights: Mutex<Vec<i64>>,
+    forward_headers: Vec<HeaderName>,
+    dispatch: &'static DispatchConfig,
+    clock: Arc<dyn Clock>,
+    coalesce_window: Option<Duration>,
+    in_flight: Mutex<HashMap<InFlightKey, Instant>>,
+    debounce: Option<Duration>,
+    /// The latest generation of each debounced command still being held.
+    debouncing: Mutex<HashMap<InFlightKey, u64>>,
+    /// The dispatch socket, when commands go over one a request can wait on.
+    reply_socket: Option<PathBuf>,
+    journal: Option<Journal>,
+}
+
+type InFlightKey = (String, Action, Option<String>);
+
+pub enum Dispatched {
+    Sent,
+    /// The request waited and the script exited successfully.
+    Completed,
+    Coalesced,
+    /// A later identical command arrived within the debounce window and is
+    /// sent in its place.
+    Debounced,
+    Ignored,
+    RefNotMatched,
+    /// The delivery was already taken, either by an earlier request or by
+    /// dispatch when a waiting caller's command reached it.
+    Duplicate,
+}
+
+#[derive(Serialize)]
+struct CoalescedBody {
+    coalesced: bool,
+}
+
+#[derive(Serialize)]
+struct DebouncedBody {
+    debounced: bool,
+}
+
+#[derive(Serialize)]
+struct DuplicateBody {
+    duplicate: bool,
+}
+
+#[derive(Serialize)]
+struct CompletedBody {
+    exit_code: i32,
+}
+
+#[derive(Serialize)]
+struct IgnoredBody {
+    ignored: bool,
+}
+
+#[derive(Default, Deserialize)]
+struct PushPayload {
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    after: Option<String>,
+    pusher: Option<Pusher>,
+    repository: Option<Repository>,
+}
+
+#[derive(Deserialize)]
+struct Pusher {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    name: Option<String>,
+}
+
+impl IntoResponse for Dispatched {
+    fn into_response(self) -> axum::response::Response {
+        let (outcome, mut res) = match self {
+            Dispatched::Sent => ("sent", ().into_response()),
+            Dispatched::Completed => (
+                "completed",
+                Json(CompletedBody { exit_code: 0 }).into_response(),
+            ),
+            Dispatched::Ignored => ("ignored", StatusCode::NO_CONTENT.into_response()),
+            Dispatched::RefNotMatched => (
+                "ref_not_matched",
+                Json(IgnoredBody { ignored: true }).into_response(),
+            ),
+            Dispatched::Coalesced => (
+                "coalesced",
+                Json(CoalescedBody { coalesced: true }).into_response(),
+            ),
+            Dispatched::Debounced => (
+                "debounced",
+                Json(DebouncedBody { debounced: true }).into_response(),
+            ),
+            Dispatched::Duplicate => (
+                "duplicate",
+                Json(DuplicateBody { duplicate: true }).into_response(),
+            ),
+        };
+        res.extensions_mut().insert(Outcome(outcome));
+        res
+    }
+}
+
+impl Dispatcher {
+    pub fn new(config: &'static Config) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: &'static Config, clock: Arc<dyn Clock>) -> Self {
+        let idle_timeout = Duration::from_secs(config.webhooks.pipe_idle_timeout_secs);
+        let queue_len = config.webhooks.dispatch_queue_len;
+        let transport = config.webhooks.transport;
+        let sinks: Vec<_> = std::iter::once(Sink::new(
+            command_sink(transport, &config.webhooks.pipe),
+            1,
+            idle_timeout,
+            queue_len,
+        ))
+        .chain(config.webhooks.sinks.iter().map(|sink| {
+            Sink::new(
+                command_sink(sink.transport.unwrap_or(transport), &sink.pipe),
+                sink.weight,
+                idle_timeout,
+                queue_len,
+            )
+        }))
+        .collect();
+
+        let current_weights = Mutex::new(vec![0; sinks.len()]);
+
+        let forward_headers = config
+            .dispatch
+            .forward_headers
+            .iter()
+            .filter_map(|name| match HeaderName::from_bytes(name.as_bytes()) {
+                Ok(name) if name == http::header::AUTHORIZATION => {
+                    tracing::warn!("refusing to forward authorization header");
+                    None
+                }
+                Ok(name) => Some(name),
+                Err(_) => {
+                    tracing::warn!("ignoring invalid forwarded header name: {name}");
+                    None
+                }
+            })
+            .collect();
+
+        Dispatcher {
+            sinks,
+            current_weights,
+            forward_headers,
+            dispatch: &config.dispatch,
+            clock,
+            coalesce_window: config
+                .webhooks
+                .coalesce_window_secs
+                .map(Duration::from_secs),
+            in_flight: Mutex::new(HashMap::new()),
+            debounce: Some(Duration::from_millis(config.dispatch.debounce_ms))
+                .filter(|window| !window.is_zero()),
+            debouncing: Mutex::new(HashMap::new()),
+            reply_socket: (config.webhooks.transport == Transport::Unix)
+                .then(|| config.webhooks.pipe.clone()),
+            journal: config.dispatch.journal.as_deref().map(Journal::new),
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn dispatch(
+        self: Arc<Self>,
+        Authed(client): Authed<'static>,
+        action: Action,
+        environment: Option<String>,
+        headers: &HeaderMap,
+        body: &[u8],
+        wait: bool,
+    ) -> Result<Dispatched, DispatchError> {
+        if ignores_event(client, headers) {
+            return Ok(Dispatched::Ignored);
+        }
+
+        if let Some(filter) = client.ref_filter.as_deref() {
+            let git_ref = serde_json::from_slice::<PushPayload>(body)
+                .ok()
+                .and_then(|payload| payload.git_ref);
+            if !git_ref.as_deref().is_some_and(|r| glob_matches(filter, r)) {
+                tracing::info!(
+                    "ignoring ref {} from {}, does not match {filter}",
+                    git_ref.as_deref().unwrap_or("(none)"),
+                    client.display_name()
+                );
+                return Ok(Dispatched::RefNotMatched);
+            }
+        }
+
+        let project = self.resolve_project(client, action, headers, body)?;
+        let project = project.as_str();
+
+        if !client.permissions.contains(&action) {
+            tracing::info!(
+                "{} not permitted to {action} {project}",
+                client.display_name()
+            );
+            return Err(DispatchError::ProjectForbidden {
+                project: project.to_string(),
+                action,
+            });
+        }
+
+        if let Some(environment) = environment.as_ref() {
+            if !client.environments.contains(environment) {
+                tracing::info!(
+                    "environment {environment} not allowed for {}",
+                    client.display_name()
+                );
+                return Err(DispatchError::EnvironmentNotAllowed);
+            }
+        }
+
+        let key = (project.to_string(), action, environment.clone());
+        if !self.begin_in_flight(&key) {
+            tracing::info!(
+                "coalescing {action} {project} from {}",
+                client.display_name()
+            );
+            return Ok(Dispatched::Coalesced);
+        }
+
+        let mut cmd = self.command(Some(client), project, action, environment, headers, body);
+        tracing::info!("dispatching: {} ({})", cmd, client.display_name());
+
+        if let Err(err) = self.record(&mut cmd).await {
+            self.end_in_flight(&key);
+            return Err(err);
+        }
+
+        let result = if wait {
+            cmd.reply = true;
+            match self.send_and_wait(client, &cmd).await {
+                Ok(CommandResult {
+                    duplicate: true, ..
+                }) => Ok(Dispatched::Duplicate),
+                Ok(CommandResult {
+                    exit_code: Some(0), ..
+                }) => Ok(Dispatched::Completed),
+                Ok(result) => Err(DispatchError::ScriptFailed {
+                    exit_code: result.exit_code,
+                    stderr: result.stderr,
+                }),
+                Err(err) => Err(err),
+            }
+        } else if let Some(window) = self.debounce {
+            self.clone()
+                .debounce(client, key.clone(), cmd.clone(), window)
+                .await
+        } else {
+            self.send(self.write_timeout(client), &cmd)
+                .await
+                .map(|()| Dispatched::Sent)
+        };
+        if result.is_err() {
+            self.end_in_flight(&key);
+            self.forget(&cmd).await;
+        }
+        result
+    }
+
+    /// Journals the command before it is sent, so dispatch can replay it if
+    /// it restarts before reading it.
+    async fn record(&self, cmd: &mut config::Command) -> Result<(), DispatchError> {
+        let journal = match self.journal.clone() {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+
+        cmd.journal_id = Some(Journal::next_id());
+        let entry = cmd.clone();
+        tokio::task::spawn_blocking(move || journal.append(&entry))
+            .await
+            .map_err(|_| DispatchError::JournalFailed)?
+            .map_err(|err| {
+                tracing::error!("unable to journal command: {err}");
+                DispatchError::JournalFailed
+            })
+    }
+
+    /// A command that failed to send is left to the client to retry, replaying
+    /// it as well would run it twice.
+    async fn forget(&self, cmd: &config::Command) {
+        let (journal, id) = match (self.journal.clone(), cmd.journal_id.clone()) {
+            (Some(journal), Some(id)) => (journal, id),
+            _ => return,
+        };
+
+        let removed = tokio::task::spawn_blocking(move || journal.remove(&id)).await;
+        if let Ok(Err(err)) = removed {
+            tracing::error!("unable to remove unsent command from journal: {err}");
+        }
+    }
+
+    /// The project named in the request's header, or the client's only one.
+    /// Projects templated with `{repo}` are filled in from the payload, and
+    /// must then name a directory right under one of the `scripts_dir`s so a
+    /// payload can never pick a path.
+    fn resolve_project(
+        &self,
+        client: &ClientConfig,
+        action: Action,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<String, DispatchError> {
+        let repo = client
+            .projects
+            .iter()
+            .any(|project| project.contains(REPO_PLACEHOLDER))
+            .then(|| serde_json::from_slice::<PushPayload>(body).ok())
+            .flatten()
+            .and_then(|payload| payload.repository?.name);
+
+        let projects: Vec<(String, bool)> = client
+            .projects
+            .iter()
+            .filter_map(|project| match repo.as_deref() {
+                _ if !project.contains(REPO_PLACEHOLDER) => Some((project.clone(), false)),
+                Some(repo) => Some((project.replace(REPO_PLACEHOLDER, repo), true)),
+                None => None,
+            })
+            .collect();
+
+        let (project, templated) = match headers.get(&PROJECT_HEADER).map(|v| v.to_str()) {
+            Some(Ok(requested)) => projects
+                .into_iter()
+                .find(|(project, _)| project == requested)
+                .ok_or_else(|| {
+                    tracing::info!(
+                        "{} not permitted to use project {requested}",
+                        client.display_name()
+                    );
+                    DispatchError::ProjectForbidden {
+                        project: requested.to_string(),
+                        action,
+                    }
+                })?,
+            Some(Err(_)) => return Err(DispatchError::ProjectRequired),
+            None => match <[_; 1]>::try_from(projects) {
+                Ok([project]) => project,
+                Err(_) => {
+                    tracing::info!("{} did not name a project", client.display_name());
+                    return Err(DispatchError::ProjectRequired);
+                }
+            },
+        };
+
+        if templated && !self.has_scripts(&project) {
+            tracing::warn!(
+                "{} resolved project {project:?} which has no script directory",
+                client.display_name()
+            );
+            return Err(DispatchError::ProjectForbidden { project, action });
+        }
+        Ok(project)
+    }
+
+    /// Whether `project` is a plain name for a directory in a `scripts_dir`.
+    fn has_scripts(&self, project: &str) -> bool {
+        let plain = !project.starts_with('.')
+            && !project.is_empty()
+            && project
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !plain {
+            return false;
+        }
+
+        self.dispatch.scripts_dirs.iter().any(|root| {
+            match (root.canonicalize(), root.join(project).canonicalize()) {
+                (Ok(root), Ok(dir)) => dir.is_dir() && dir.parent() == Some(root.as_path()),
+                _ => false,
+            }
+        })
+    }
+
+    /// Sends a command an operator asked for, without any of the checks made
+    /// of client requests.
+    pub async fn trigger(
+        &self,
+        project: &str,
+        action: Action,
+    ) -> Result<Dispatched, DispatchError> {
+        let mut cmd = self.command(None, project, action, None, &HeaderMap::new(), &[]);
+        tracing::info!("dispatching: {cmd} (manual trigger)");
+
+        self.record(&mut cmd).await?;
+        let write_timeout = Duration::from_millis(self.dispatch.dispatch_timeout_ms);
+        let result = self.send(write_timeout, &cmd).await;
+        if result.is_err() {
+            self.forget(&cmd).await;
+        }
+        result.map(|()| Dispatched::Sent)
+    }
+
+    /// Holds the command until no identical one has arrived for `window`,
+    /// then sends it. A command superseded in the meantime is dropped. Runs
+    /// as its own task so the command is still sent if the client hangs up.
+    async fn debounce(
+        self: Arc<Self>,
+        client: &'static ClientConfig,
+        key: InFlightKey,
+        cmd: config::Command,
+        window: Duration,
+    ) -> Result<Dispatched, DispatchError> {
+        let generation = {
+            let mut debouncing = self.debouncing.lock().unwrap();
+            let generation = debouncing.entry(key.clone()).or_insert(0);
+            *generation += 1;
+            *generation
+        };
+
+        let debounced = tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+
+            let latest = {
+                let mut debouncing = self.debouncing.lock().unwrap();
+                let latest = debouncing.get(&key) == Some(&generation);
+                if latest {
+                    debouncing.remove(&key);
+                }
+                latest
+            };
+            if !latest {
+                tracing::info!("debounced: {cmd} ({})", client.display_name());
+                self.forget(&cmd).await;
+                return Ok(Dispatched::Debounced);
+            }
+
+            self.send(self.write_timeout(client), &cmd)
+                .await
+                .map(|()| Dispatched::Sent)
+        });
+        debounced.await.unwrap_or(Err(DispatchError::WriterStopped))
+    }
+
+    /// Records a dispatch for `key`, false if one was already recorded within
+    /// the coalesce window. Always true when coalescing is disabled.
+    fn begin_in_flight(&self, key: &InFlightKey) -> bool {
+        let window = match self.coalesce_window {
+            Some(window) => window,
+            None => return true,
+        };
+
+        let now = self.clock.now();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.retain(|_, started| now.duration_since(*started) < window);
+        if in_flight.contains_key(key) {
+            return false;
+        }
+        in_flight.insert(key.clone(), now);
+        true
+    }
+
+    /// A failed dispatch should not swallow the client's retry.
+    fn end_in_flight(&self, key: &InFlightKey) {
+        if self.coalesce_window.is_some() {
+            self.in_flight.lock().unwrap().remove(key);
+        }
+    }
+
+    fn command(
+        &self,
+        client: Option<&ClientConfig>,
+        project: &str,
+        action: Action,
+        environment: Option<String>,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> config::Command {
+        let delivery_id = headers
+            .get(&GITHUB_DELIVERY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let request_id = headers
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+
+        let headers = self
+            .forward_headers
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name)?.to_str().ok()?;
+                Some((name.as_str().to_string(), value.to_string()))
+            })
+            .collect();
+
+        #[cfg(feature = "otel")]
+        let trace_context = crate::telemetry::current_context();
+        #[cfg(not(feature = "otel"))]
+        let trace_context = Default::default();
+
+        let payload = if body.is_empty() {
+            None
+        } else if body.len() > self.dispatch.max_payload_bytes {
+            tracing::warn!(
+                "not forwarding {} byte payload, over the {} byte limit",
+                body.len(),
+                self.dispatch.max_payload_bytes
+            );
+            None
+        } else {
+            Some(body.to_vec())
+        };
+
+        let push = serde_json::from_slice::<PushPayload>(body).unwrap_or_default();
+
+        config::Command {
+            action,
+            project: project.to_string(),
+            headers,
+            environment,
+            priority: self.dispatch.priority(action),
+            trace_context,
+            payload,
+            delivery_id,
+            request_id,
+            client: client.map(|client| client.name.clone()),
+            git_ref: push.git_ref,
+            sha: push.after,
+            pusher: push.pusher.and_then(|pusher| pusher.name),
+            reply: false,
+            journal_id: None,
+        }
+    }
+
+    fn write_timeout(&self, client: &ClientConfig) -> Duration {
+        Duration::from_millis(
+            client
+                .dispatch_timeout_ms
+                .unwrap_or(self.dispatch.dispatch_timeout_ms),
+        )
+    }
+
+    async fn send(
+        &self,
+        write_timeout: Duration,
+        cmd: &config::Command,
+    ) -> Result<(), DispatchError> {
+        let started = Instant::now();
+        let result = self.write(write_timeout, cmd).await;
+        METRICS.dispatched(cmd.action, &result, started.elapsed());
+        result
+    }
+
+    /// Retries transient failures with a doubling delay, for as long as the
+    /// attempts and `write_timeout` allow.
+    async fn write(
+        &self,
+        write_timeout: Duration,
+        cmd: &config::Command,
+    ) -> Result<(), DispatchError> {
+        let line = format!("{}\n", cmd.to_wire());
+        let deadline = tokio::time::Instant::now() + write_timeout;
+        let mut delay = Duration::from_millis(self.dispatch.dispatch_retry_delay_ms);
+        let mut attempt = 1;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            match self.write_once(remaining, &line).await {
+                Err(err)
+                    if err.is_transient()
+                        && attempt < self.dispatch.dispatch_attempts
+                        && tokio::time::Instant::now() + delay < deadline =>
+                {
+                    tracing::debug!("retrying dispatch in {delay:?} after {err}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// One try at each sink until one takes the line.
+    async fn write_once(&self, write_timeout: Duration, line: &str) -> Result<(), DispatchError> {
+        let mut tried = vec![false; self.sinks.len()];
+        let mut last_err = DispatchError::BadPipe;
+
+        while let Some(idx) = self.next_sink(&tried) {
+            tried[idx] = true;
+            let sink = &self.sinks[idx];
+
+            match sink
+                .write(line.as_bytes(), self.clock.now(), write_timeout)
+                .await
+            {
+                Ok(()) => {
+                    METRICS.dispatch_written(line.len());
+                    sink.record_success();
+                    return Ok(());
+                }
+                Err(err) => {
+                    SINK_ERRORS.warn(format_args!(
+                        "dispatch to sink {} failed: {}",
+                        sink.pipe.display(),
+                        err
+                    ));
+                    // a full queue is a busy sink, not a broken one
+                    if !matches!(err, DispatchError::Overloaded) {
+                        sink.record_failure(self.clock.now());
+                    }
+                    last_err = err;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Sends the command on a connection of its own, bypassing the sinks, and
+    /// waits for dispatch to report how the script finished.
+    async fn send_and_wait(
+        &self,
+        client: &ClientConfig,
+        cmd: &config::Command,
+    ) -> Result<CommandResult, DispatchError> {
+        let socket = self
+            .reply_socket
+            .as_deref()
+            .ok_or(DispatchError::WaitUnsupported)?;
+        let line = format!("{}\n", cmd.to_wire());
+
+        let started = Instant::now();
+        let deadline = tokio::time::Instant::now() + self.write_timeout(client);
+        let written = timeout_at(deadline, async {
+            let mut stream = UnixStream::connect(socket).await?;
+            stream.write_all(line.as_bytes()).await?;
+            // lets dispatch see the end of the commands while the read half
+            // stays open for the result
+            stream.shutdown().await?;
+            Ok::<_, std::io::Error>(stream)
+        })
+        .await
+        .map_err(|_| DispatchError::Timeout)
+        .and_then(|written| written.map_err(DispatchError::from));
+        METRICS.dispatched(
+            cmd.action,
+            &written.as_ref().map(|_| ()).map_err(Clone::clone),
+            started.elapsed(),
+        );
+        if written.is_ok() {
+            METRICS.dispatch_written(line.len());
+        }
+
+        let mut reply = String::new();
+        BufReader::new(written?).read_line(&mut reply).await?;
+        CommandResult::from_wire(&reply).map_err(|_| {
+            tracing::warn!("dispatch closed the connection without a result");
+            DispatchError::BadPipe
+        })
+    }
+
+    /// Smooth weighted round-robin over the healthy sinks that have not been
+    /// tried yet, falling back to unhealthy sinks when no healthy ones remain.
+    fn next_sink(&self, tried: &[bool]) -> Option<usize> {
+        let now = self.clock.now();
+        let healthy: Vec<usize> = (0..self.sinks.len())
+            .filter(|&idx| !tried[idx] && self.sinks[idx].is_healthy(now))
+            .collect();
+
+        let candidates = if healthy.is_empty() {
+            (0..self.sinks.len()).filter(|&idx| !tried[idx]).collect()
+        } else {
+            healthy
+        };
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut current = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best = candidates[0];
+        for &idx in &candidates {
+            let weight = self.sinks[idx].weight as i64;
+            current[idx] += weight;
+            total += weight;
+            if current[idx] > current[best] {
+                best = idx;
+            }
+        }
+        current[best] -= total;
+
+        Some(best)
+    }
+}
+
+struct Sink {
+    pipe: PathBuf,
+    weight: u32,
+    consecutive_failures: AtomicU32,
+    unhealthy_until: Mutex<Option<Instant>>,
+    writes: mpsc::Sender<WriteJob>,
+}
+
+struct WriteJob {
+    line: Vec<u8>,
+    now: Instant,
+    deadline: tokio::time::Instant,
+    done: oneshot::Sender<Result<(), DispatchError>>,
+}
+
+impl Sink {
+    fn new(
+        target: Box<dyn CommandSink>,
+        weight: u32,
+        idle_timeout: Duration,
+        queue_len: usize,
+    ) -> Self {
+        let pipe = target.path().to_path_buf();
+        let (writes, jobs) = mpsc::channel(queue_len.max(1));
+        tokio::spawn(writer(target, idle_timeout, jobs));
+
+        Sink {
+            pipe,
+            weight: weight.max(1),
+            consecutive_failures: AtomicU32::new(0),
+            unhealthy_until: Mutex::new(None),
+            writes,
+        }
+    }
+
+    /// Queues the line for the sink's writer, failing straight away with
+    /// `Overloaded` when the queue is full. A line whose write has started is
+    /// always finished, even if the caller has timed out or gone away, so the
+    /// pipe never sees half a command.
+    async fn write(
+        &self,
+        line: &[u8],
+        now: Instant,
+        write_timeout: Duration,
+    ) -> Result<(), DispatchError> {
+        let deadline = tokio::time::Instant::now() + write_timeout;
+        let (done, result) = oneshot::channel();
+        let job = WriteJob {
+            line: line.to_vec(),
+            now,
+            deadline,
+            done,
+        };
+
+        self.writes.try_send(job).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => DispatchError::Overloaded,
+            mpsc::error::TrySendError::Closed(_) => DispatchError::WriterStopped,
+        })?;
+
+        timeout_at(deadline, result)
+            .await
+            .map_err(|_| DispatchError::Timeout)?
+            .map_err(|_| DispatchError::WriterStopped)?
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        match *self.unhealthy_until.lock().unwrap() {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        *self.unhealthy_until.lock().unwrap() = None;
+    }
+
+    fn record_failure(&self, now: Instant) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= SINK_FAILURE_THRESHOLD {
+            tracing::warn!(
+                "marking sink {} unhealthy after {} failures",
+                self.pipe.display(),
+                failures
+            );
+            *self.unhealthy_until.lock().unwrap() = Some(now + SINK_COOLDOWN);
+        }
+    }
+}
+
+type Connection = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Something commands can be written to, one per line.
+#[async_trait::async_trait]
+trait CommandSink: Send + Sync {
+    fn path(&self) -> &Path;
+
+    async fn connect(&self) -> std::io::Result<Connection>;
+}
+
+struct Fifo(PathBuf);
+
+#[async_trait::async_trait]
+impl CommandSink for Fifo {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    async fn connect(&self) -> std::io::Result<Connection> {
+        let file = OpenOptions::new().append(true).open(&self.0).await?;
+        Ok(Box::new(file))
+    }
+}
+
+struct Socket(PathBuf);
+
+#[async_trait::async_trait]
+impl CommandSink for Socket {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    async fn connect(&self) -> std::io::Result<Connection> {
+        let stream = UnixStream::connect(&self.0).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+fn command_sink(transport: Transport, path: &Path) -> Box<dyn CommandSink> {
+    match transport {
+        Transport::Fifo => Box::new(Fifo(path.to_path_buf())),
+        Transport::Unix => Box::new(Socket(path.to_path_buf())),
+    }
+}
+
+/// A connection kept open between dispatches, until it has gone unused for
+/// the sink's idle timeout.
+struct PipeHandle {
+    conn: Connection,
+    last_used: Instant,
+}
+
+/// Owns the sink's connection and writes queued lines one at a time, closing
+/// it once idle so the dispatch binary sees the end of the pipe. Exits when
+/// the sink is dropped.
+async fn writer(
+    target: Box<dyn CommandSink>,
+    idle_timeout: Duration,
+    mut jobs: mpsc::Receiver<WriteJob>,
+) {
+    let mut handle: Option<PipeHandle> = None;
+
+    loop {
+        let job = if handle.is_some() {
+            match timeout(idle_timeout, jobs.recv()).await {
+                Ok(job) => job,
+                Err(_) => {
+                    handle = None;
+                    continue;
+                }
+            }
+        } else {
+            jobs.recv().await
+        };
+        let job = match job {
+            Some(job) => job,
+            None => return,
+        };
+
+        // the caller has already been told it timed out, writing now would
+        // dispatch a command the client will retry
+        if tokio::time::Instant::now() >= job.deadline {
+            let _ = job.done.send(Err(DispatchError::Timeout));
+            continue;
+        }
+
+        let result = write_job(target.as_ref(), &mut handle, &job, idle_timeout).await;
+        let _ = job.done.send(result);
+    }
+}
+
+async fn write_job(
+    target: &dyn CommandSink,
+    handle: &mut Option<PipeHandle>,
+    job: &WriteJob,
+    idle_timeout: Duration,
+) -> Result<(), DispatchError> {
+    if handle
+        .as_ref()
+        .is_some_and(|h| job.now.duration_since(h.last_used) >= idle_timeout)
+    {
+        *handle = None;
+    }
+
+    let reused = handle.is_some();
+    if !reused {
+        *handle = Some(PipeHandle {
+            conn: connect(target, job.deadline).await?,
+            last_used: job.now,
+        });
+    }
+
+    let mut result = write_line(handle, &job.line).await;
+
+    // a reader that went away since the handle was last used fails the write
+    // outright with nothing written, so a fresh handle can retry
+    if result.is_err() && reused {
+        *handle = None;
+        *handle = Some(PipeHandle {
+            conn: connect(target, job.deadline).await?,
+            last_used: job.now,
+        });
+        result = write_line(handle, &job.line).await;
+    }
+
+    match result {
+        Ok(()) if !idle_timeout.is_zero() => {
+            if let Some(handle) = handle.as_mut() {
+                handle.last_used = job.now;
+            }
+        }
+        _ => *handle = None,
+    }
+
+    result.map_err(DispatchError::from)
+}
+
+/// Whether the request is for an event the client doesn't dispatch on, which
+/// the headers alone can tell.
+pub fn ignores_event(client: &ClientConfig, headers: &HeaderMap) -> bool {
+    let event = match headers
+        .get(&GITHUB_EVENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(event) => event,
+        None => return false,
+    };
+    let allowed = event != "ping" && (client.events.is_empty() || client.events.contains(event));
+    if !allowed {
+        tracing::info!("ignoring {event} event from {}", client.display_name());
+    }
+    !allowed
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let text = match text.strip_prefix(prefix) {
+                Some(text) => text,
+                None => return false,
+            };
+            text.char_indices()
+                .map(|(idx, _)| idx)
+                .chain(std::iter::once(text.len()))
+                .any(|idx| glob_matches(rest, &text[idx..]))
+        }
+    }
+}
+
+async fn connect(
+    target: &dyn CommandSink,
+    deadline: tokio::time::Instant,
+) -> Result<Connection, DispatchError> {
+    let conn = timeout_at(deadline, target.connect())
+        .await
+        .map_err(|_| DispatchError::Timeout)??;
+    Ok(conn)
+}
+
+async fn write_line(handle: &mut Option<PipeHandle>, line: &[u8]) -> std::io::Result<()> {
+    let conn = &mut handle.as_mut().expect("pipe handle is open").conn;
+    conn.write_all(line).await?;
+    conn.flush().await
+}
+
+#[derive(Debug, Clone)]
+pub enum DispatchError {
+    /// An IO error none of the others describe.
+    BadPipe,
+    /// The task writing the pipe, or holding a debounced command, went away
+    /// before the command was written. Trying again can't help.
+    WriterStopped,
+    /// Nothing at the pipe path, a config mistake or dispatch never started.
+    PipeNotFound,
+    /// The reader went away, usually while dispatch restarts.
+    PipeBroken,
+    PipePermission,
+    Timeout,
+    Overloaded,
+    EnvironmentNotAllowed,
+    ProjectRequired,
+    ProjectForbidden {
+        project: String,
+        action: Action,
+    },
+    WaitUnsupported,
+    ScriptFailed {
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    JournalFailed,
+}
+
+impl DispatchError {
+    /// Failures a moment later may not repeat, dispatch restarting or busy.
+    fn is_transient(&self) -> bool {
+        matches!(self, DispatchError::PipeBroken | DispatchError::Overloaded)
+    }
+}
+
+impl std::error::Error for DispatchError {}
+
+impl std::fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<std::io::Error> for DispatchError {
+    fn from(err: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::NotFound => DispatchError::PipeNotFound,
+            ErrorKind::PermissionDenied => DispatchError::PipePermission,
+            ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof => DispatchError::PipeBroken,
+            _ => DispatchError::BadPipe,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::clock::FakeClock;
+    use crate::test_support::{self, CONFIG};
+
+    /// Hands the test the reading end of every connection, which buffers only
+    /// `capacity` bytes so the test decides how fast the pipe drains. The
+    /// first `failures` connects fail as if dispatch had closed the pipe.
+    struct TestSink {
+        capacity: usize,
+        failures: AtomicU32,
+        connections: mpsc::UnboundedSender<DuplexStream>,
+    }
+
+    impl TestSink {
+        fn new(capacity: usize, failures: u32) -> (Self, mpsc::UnboundedReceiver<DuplexStream>) {
+            let (connections, readers) = mpsc::unbounded_channel();
+            let sink = TestSink {
+                capacity,
+                failures: AtomicU32::new(failures),
+                connections,
+            };
+            (sink, readers)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl CommandSink for TestSink {
+        fn path(&self) -> &Path {
+            Path::new("test")
+        }
+
+        async fn connect(&self) -> std::io::Result<Connection> {
+            let failed = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+            if failed.is_ok() {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let (writer, reader) = tokio::io::duplex(self.capacity);
+            let _ = self.connections.send(reader);
+            Ok(Box::new(writer))
+        }
+    }
+
+    /// Writes only to `sink`, in place of the configured pipes.
+    fn with_sink(config: &'static Config, sink: TestSink) -> Dispatcher {
+        let mut dispatcher = Dispatcher::new(config);
+        dispatcher.sinks = vec![Sink::new(
+            Box::new(sink),
+            1,
+            Duration::from_secs(config.webhooks.pipe_idle_timeout_secs),
+            config.webhooks.dispatch_queue_len,
+        )];
+        dispatcher
+    }
+
+    async fn read_text(reader: &mut DuplexStream, len: usize) -> String {
+        let mut line = vec![0; len];
+        reader.read_exact(&mut line).await.unwrap();
+        String::from_utf8(line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dropped_request_still_writes_the_whole_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let (sink, mut readers) = TestSink::new(16, 0);
+        let dispatcher = with_sink(config, sink);
+
+        let mut cmd = config::Command::new(Action::Deploy, "proj").unwrap();
+        cmd.payload = Some(vec![b'x'; 4096]);
+        let line = format!("{}\n", cmd.to_wire());
+
+        let mut reader = {
+            let send = dispatcher.send(Duration::from_secs(5), &cmd);
+            tokio::pin!(send);
+            let mut reader = tokio::select! {
+                _ = &mut send => panic!("write finished with nothing read"),
+                reader = readers.recv() => reader.unwrap(),
+            };
+            // the write has started and filled the pipe's buffer, the request
+            // goes away part way through it
+            let mut start = [0; 16];
+            reader.read_exact(&mut start).await.unwrap();
+            assert_eq!(&start, &line.as_bytes()[..16]);
+            reader
+        };
+
+        let rest = read_text(&mut reader, line.len() - 16).await;
+        assert_eq!(rest, line[16..]);
+        let mut more = [0; 1];
+        let extra = timeout(Duration::from_millis(50), reader.read(&mut more)).await;
+        assert!(extra.is_err(), "nothing follows the command");
+    }
+
+    #[tokio::test]
+    async fn forbidden_project_reports_the_requested_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let dispatcher = Dispatcher::new(config);
+        let client = &config.clients["abc"];
+
+        let mut headers = HeaderMap::new();
+        headers.insert(&PROJECT_HEADER, "other".parse().unwrap());
+        let err = dispatcher
+            .resolve_project(client, Action::Rollback, &headers, b"")
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                DispatchError::ProjectForbidden { project, action: Action::Rollback } if project == "other"
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        assert!(DispatchError::PipeBroken.is_transient());
+        assert!(DispatchError::Overloaded.is_transient());
+        assert!(!DispatchError::BadPipe.is_transient());
+        assert!(!DispatchError::WriterStopped.is_transient());
+        assert!(!DispatchError::PipeNotFound.is_transient());
+        assert!(!DispatchError::Timeout.is_transient());
+    }
+
+    #[tokio::test]
+    async fn slow_pipe_times_out_at_the_clients_bound() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("{CONFIG}dispatch_timeout_ms = 200\n");
+        let config = test_support::config(&toml, dir.path());
+        let (sink, _readers) = TestSink::new(16, 0);
+        let dispatcher = with_sink(config, sink);
+        let client = &config.clients["abc"];
+
+        let mut cmd = config::Command::new(Action::Deploy, "proj").unwrap();
+        cmd.payload = Some(vec![b'x'; 4096]);
+        let started = Instant::now();
+        let result = dispatcher
+            .send(dispatcher.write_timeout(client), &cmd)
+            .await;
+
+        assert!(matches!(result, Err(DispatchError::Timeout)), "{result:?}");
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(900), "{elapsed:?}");
+    }
+
+    fn with_event(mut req: Request<axum::body::Body>, event: &str) -> Request<axum::body::Body> {
+        req.headers_mut()
+            .insert(&GITHUB_EVENT_HEADER, event.parse().unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn push_event_is_dispatched() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        let req = with_event(test_support::signed("/deploy", "{}"), "push");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn ping_event_is_not_dispatched() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        let req = with_event(test_support::signed("/deploy", "{}"), "ping");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(lines.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    async fn deploy(
+        config: &'static Config,
+        dispatcher: Dispatcher,
+        body: &str,
+    ) -> Result<Dispatched, DispatchError> {
+        let client = &config.clients["abc"];
+        Arc::new(dispatcher)
+            .dispatch(
+                Authed(client),
+                Action::Deploy,
+                None,
+                &HeaderMap::new(),
+                body.as_bytes(),
+                false,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn ref_filter_decides_what_is_dispatched() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("{CONFIG}ref_filter = \"refs/heads/release-*\"\n");
+        let config = test_support::config(&toml, dir.path());
+
+        let (sink, mut readers) = TestSink::new(4096, 0);
+        let matching = deploy(
+            config,
+            with_sink(config, sink),
+            r#"{"ref": "refs/heads/release-1.2"}"#,
+        )
+        .await;
+        assert!(matches!(matching, Ok(Dispatched::Sent)));
+        assert!(readers.try_recv().is_ok());
+
+        for body in [r#"{"ref": "refs/heads/main"}"#, "{}", "not json"] {
+            let (sink, mut readers) = TestSink::new(4096, 0);
+            let result = deploy(config, with_sink(config, sink), body).await;
+            assert!(matches!(result, Ok(Dispatched::RefNotMatched)), "{body}");
+            assert!(readers.try_recv().is_err(), "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn flood_is_turned_away_as_overloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG
+            .replace("[dispatch]", "dispatch_queue_len = 1\n\n[dispatch]")
+            .replace("[clients.abc]", "dispatch_attempts = 1\n\n[clients.abc]");
+        let config = test_support::config(&toml, dir.path());
+        let (sink, mut readers) = TestSink::new(16, 0);
+        let dispatcher = Arc::new(with_sink(config, sink));
+
+        let sends: Vec<_> = (0..8)
+            .map(|n| {
+                let dispatcher = dispatcher.clone();
+                let mut cmd = config::Command::new(Action::Deploy, &format!("proj{n}")).unwrap();
+                cmd.payload = Some(vec![b'x'; 1024]);
+                tokio::spawn(async move {
+                    let result = dispatcher.send(Duration::from_secs(5), &cmd).await;
+                    (cmd, result)
+                })
+            })
+            .collect();
+        // every send has either been queued or turned away before the pipe
+        // drains
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut reader = BufReader::new(readers.recv().await.unwrap());
+        let (lines, mut written) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let _ = lines.send(std::mem::take(&mut line));
+            }
+        });
+
+        let mut sent = Vec::new();
+        for send in sends {
+            let (cmd, result) = send.await.unwrap();
+            match result {
+                Ok(()) => sent.push(cmd),
+                Err(DispatchError::Overloaded) => (),
+                Err(err) => panic!("unexpected {err:?}"),
+            }
+        }
+        assert!(!sent.is_empty() && sent.len() < 8, "{} sent", sent.len());
+
+        for cmd in sent {
+            let line = written.recv().await.unwrap();
+            let command = config::Command::from_wire(&line).unwrap();
+            assert_eq!(command.project, cmd.project);
+            assert_eq!(command.payload, cmd.payload);
+        }
+    }
+
+    /// Resolves the project `abc` asks for, or its only one when `requested`
+    /// is None.
+    fn resolve(toml: &str, requested: Option<&str>, body: &str) -> Result<String, DispatchError> {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(toml, dir.path());
+        let dispatcher = Dispatcher::new(config);
+
+        let mut headers = HeaderMap::new();
+        if let Some(requested) = requested {
+            headers.insert(&PROJECT_HEADER, requested.parse().unwrap());
+        }
+        dispatcher.resolve_project(
+            &config.clients["abc"],
+            Action::Deploy,
+            &headers,
+            body.as_bytes(),
+        )
+    }
+
+    #[tokio::test]
+    async fn single_project_is_used_without_naming_it() {
+        assert_eq!(resolve(CONFIG, None, "").unwrap(), "proj");
+        assert_eq!(resolve(CONFIG, Some("proj"), "").unwrap(), "proj");
+    }
+
+    #[tokio::test]
+    async fn one_of_several_projects_must_be_named() {
+        let toml = CONFIG.replace("project = \"proj\"", "project = [\"proj\", \"site\"]");
+
+        assert_eq!(resolve(&toml, Some("site"), "").unwrap(), "site");
+        assert!(matches!(
+            resolve(&toml, None, ""),
+            Err(DispatchError::ProjectRequired)
+        ));
+        assert!(matches!(
+            resolve(&toml, Some("other"), ""),
+            Err(DispatchError::ProjectForbidden { project, .. }) if project == "other"
+        ));
+    }
+
+    #[tokio::test]
+    async fn command_round_trips_over_a_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "transport = \"unix\"\n\n[dispatch]");
+        let config = test_support::config(&toml, dir.path());
+        let listener = std::os::unix::net::UnixListener::bind(dir.path().join("pipe")).unwrap();
+        let accepted = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            std::io::BufRead::read_line(&mut std::io::BufReader::new(stream), &mut line).unwrap();
+            line
+        });
+
+        let mut cmd = config::Command::new(Action::Deploy, "proj").unwrap();
+        cmd.payload = Some(br#"{"ref": "refs/heads/main"}"#.to_vec());
+        let dispatcher = Dispatcher::new(config);
+        dispatcher.send(Duration::from_secs(5), &cmd).await.unwrap();
+
+        let line = tokio::task::spawn_blocking(move || accepted.join().unwrap())
+            .await
+            .unwrap();
+        let received = config::Command::from_wire(&line).unwrap();
+        assert_eq!(received.to_wire(), cmd.to_wire());
+    }
+
+    #[tokio::test]
+    async fn unix_socket_without_a_listener_fails_straight_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG
+            .replace("[dispatch]", "transport = \"unix\"\n\n[dispatch]")
+            .replace("[clients.abc]", "dispatch_attempts = 1\n\n[clients.abc]");
+        let config = test_support::config(&toml, dir.path());
+
+        let cmd = config::Command::new(Action::Deploy, "proj").unwrap();
+        let started = Instant::now();
+        let result = Dispatcher::new(config)
+            .send(Duration::from_secs(5), &cmd)
+            .await;
+        assert!(
+            matches!(result, Err(DispatchError::PipeNotFound)),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn push_metadata_is_carried_in_the_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let body =
+            r#"{"ref": "refs/heads/main", "after": "abc123", "pusher": {"name": "octocat"}}"#;
+
+        let (sink, mut readers) = TestSink::new(4096, 0);
+        let result = deploy(config, with_sink(config, sink), body).await;
+        assert!(matches!(result, Ok(Dispatched::Sent)));
+        let mut line = String::new();
+        BufReader::new(readers.recv().await.unwrap())
+            .read_line(&mut line)
+            .await
+            .unwrap();
+
+        let command = config::Command::from_wire(&line).unwrap();
+        assert_eq!(command.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(command.sha.as_deref(), Some("abc123"));
+        assert_eq!(command.pusher.as_deref(), Some("octocat"));
+    }
+
+    #[tokio::test]
+    async fn waiting_on_a_failed_script_is_a_bad_gateway() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "transport = \"unix\"\n\n[dispatch]");
+        let listener = std::os::unix::net::UnixListener::bind(dir.path().join("pipe")).unwrap();
+        // stands in for dispatch, running a script that exits 3
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            std::io::Read::read_to_string(&mut stream, &mut line).unwrap();
+            assert!(config::Command::from_wire(&line).unwrap().reply);
+            let result = CommandResult {
+                exit_code: Some(3),
+                stderr: "deploy broke\n".into(),
+                duplicate: false,
+            };
+            std::io::Write::write_all(&mut stream, format!("{}\n", result.to_wire()).as_bytes())
+                .unwrap();
+        });
+
+        let app = test_support::app(&toml, dir.path());
+        let res = app
+            .oneshot(test_support::signed("/deploy?wait=true", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "script_failed");
+        assert_eq!(body["exit_code"], "3");
+        assert_eq!(body["stderr"], "deploy broke\n");
+    }
+
+    #[tokio::test]
+    async fn waiting_on_a_taken_delivery_is_a_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "transport = \"unix\"\n\n[dispatch]");
+        let listener = std::os::unix::net::UnixListener::bind(dir.path().join("pipe")).unwrap();
+        // stands in for dispatch, which has already run this delivery
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            std::io::Read::read_to_string(&mut stream, &mut line).unwrap();
+            let result = CommandResult {
+                exit_code: None,
+                stderr: String::new(),
+                duplicate: true,
+            };
+            std::io::Write::write_all(&mut stream, format!("{}\n", result.to_wire()).as_bytes())
+                .unwrap();
+        });
+
+        let app = test_support::app(&toml, dir.path());
+        let res = app
+            .oneshot(test_support::signed("/deploy?wait=true", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.extensions().get::<Outcome>().map(|outcome| outcome.0),
+            Some("duplicate")
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "duplicate": true }));
+    }
+
+    #[tokio::test]
+    async fn deploys_within_the_debounce_window_run_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[clients.abc]", "debounce_ms = 200\n\n[clients.abc]");
+        let config = test_support::config(&toml, dir.path());
+        let (sink, mut readers) = TestSink::new(4096, 0);
+        let dispatcher = Arc::new(with_sink(config, sink));
+
+        let mut deploys = Vec::new();
+        for n in 0..3 {
+            let dispatcher = dispatcher.clone();
+            let body = format!(r#"{{"after": "sha{n}"}}"#);
+            deploys.push(tokio::spawn(async move {
+                dispatcher
+                    .dispatch(
+                        Authed(&config.clients["abc"]),
+                        Action::Deploy,
+                        None,
+                        &HeaderMap::new(),
+                        body.as_bytes(),
+                        false,
+                    )
+                    .await
+            }));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let mut sent = 0;
+        for deploy in deploys {
+            match deploy.await.unwrap() {
+                Ok(Dispatched::Sent) => sent += 1,
+                Ok(Dispatched::Debounced) => (),
+                Ok(_) => panic!("neither sent nor debounced"),
+                Err(err) => panic!("unexpected {err:?}"),
+            }
+        }
+        assert_eq!(sent, 1);
+
+        // the last of the three is the one sent
+        let mut reader = BufReader::new(readers.recv().await.unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let command = config::Command::from_wire(&line).unwrap();
+        assert_eq!(command.sha.as_deref(), Some("sha2"));
+        line.clear();
+        let more = timeout(Duration::from_millis(100), reader.read_line(&mut line)).await;
+        assert!(more.is_err(), "a second command was sent: {line}");
+    }
+
+    #[tokio::test]
+    async fn repo_template_resolves_only_to_a_scripts_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("scripts/site")).unwrap();
+        std::fs::create_dir_all(dir.path().join("escape")).unwrap();
+        let toml = CONFIG.replace("project = \"proj\"", "project = \"{repo}\"");
+        let config = test_support::config(&toml, dir.path());
+        let dispatcher = Dispatcher::new(config);
+        let resolve = |repo: &str| {
+            let body = serde_json::json!({ "repository": { "name": repo } }).to_string();
+            dispatcher.resolve_project(
+                &config.clients["abc"],
+                Action::Deploy,
+                &HeaderMap::new(),
+                body.as_bytes(),
+            )
+        };
+
+        assert_eq!(resolve("site").unwrap(), "site");
+        for repo in ["../escape", "..", "missing"] {
+            assert!(
+                matches!(resolve(repo), Err(DispatchError::ProjectForbidden { .. })),
+                "{repo}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_failing_once_is_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(CONFIG, dir.path());
+        let (sink, mut readers) = TestSink::new(4096, 1);
+        let dispatcher = with_sink(config, sink);
+
+        let cmd = config::Command::new(Action::Deploy, "proj").unwrap();
+        dispatcher.send(Duration::from_secs(5), &cmd).await.unwrap();
+
+        let line = format!("{}\n", cmd.to_wire());
+        let mut reader = readers.recv().await.unwrap();
+        assert_eq!(read_text(&mut reader, line.len()).await, line);
+    }
+
+    #[tokio::test]
+    async fn pipe_failing_every_attempt_gives_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[clients.abc]", "dispatch_attempts = 2\n\n[clients.abc]");
+        let config = test_support::config(&toml, dir.path());
+        let (sink, mut readers) = TestSink::new(4096, 2);
+        let dispatcher = with_sink(config, sink);
+
+        let cmd = config::Command::new(Action::Deploy, "proj").unwrap();
+        let result = dispatcher.send(Duration::from_secs(5), &cmd).await;
+        assert!(
+            matches!(result, Err(DispatchError::PipeBroken)),
+            "{result:?}"
+        );
+        assert!(readers.try_recv().is_err());
+    }
+
+    /// The configured pipe with weight 1 and a second sink with weight 3.
+    fn weighted(dir: &Path, clock: Arc<FakeClock>) -> Dispatcher {
+        let toml = CONFIG.replace(
+            "[dispatch]",
+            "[[webhooks.sinks]]\npipe = \"{dir}/other\"\nweight = 3\n\n[dispatch]",
+        );
+        Dispatcher::with_clock(test_support::config(&toml, dir), clock)
+    }
+
+    fn picks(dispatcher: &Dispatcher, n: usize) -> Vec<usize> {
+        (0..n)
+            .map(|_| dispatcher.next_sink(&[false, false]).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sinks_are_picked_in_proportion_to_their_weight() {
+        let dir = tempfile::tempdir().unwrap();
+        let dispatcher = weighted(dir.path(), Arc::new(FakeClock::new()));
+
+        assert_eq!(picks(&dispatcher, 8), [1, 0, 1, 1, 1, 0, 1, 1]);
+        assert_eq!(dispatcher.next_sink(&[false, true]), Some(0));
+        assert_eq!(dispatcher.next_sink(&[true, true]), None);
+    }
+
+    #[tokio::test]
+    async fn failing_sink_is_skipped_until_its_cooldown_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(FakeClock::new());
+        let dispatcher = weighted(dir.path(), clock.clone());
+
+        for _ in 1..SINK_FAILURE_THRESHOLD {
+            dispatcher.sinks[1].record_failure(clock.now());
+        }
+        assert!(dispatcher.sinks[1].is_healthy(clock.now()));
+        dispatcher.sinks[1].record_failure(clock.now());
+        assert!(!dispatcher.sinks[1].is_healthy(clock.now()));
+        assert_eq!(picks(&dispatcher, 4), [0, 0, 0, 0]);
+
+        clock.advance(SINK_COOLDOWN - Duration::from_secs(1));
+        assert_eq!(picks(&dispatcher, 4), [0, 0, 0, 0]);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(dispatcher.sinks[1].is_healthy(clock.now()));
+        assert_eq!(picks(&dispatcher, 4), [1, 0, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn every_sink_unhealthy_falls_back_to_all_of_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(FakeClock::new());
+        let dispatcher = weighted(dir.path(), clock.clone());
+
+        for sink in &dispatcher.sinks {
+            for _ in 0..SINK_FAILURE_THRESHOLD {
+                sink.record_failure(clock.now());
+            }
+        }
+        assert_eq!(picks(&dispatcher, 4), [1, 0, 1, 1]);
+        assert_eq!(dispatcher.next_sink(&[false, true]), Some(0));
+        assert_eq!(dispatcher.next_sink(&[true, true]), None);
+    }
+}
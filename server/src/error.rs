@@ -0,0 +1,310 @@
+use axum::{
+    http::{self, HeaderValue, StatusCode},
+    response::IntoResponse,
+    BoxError, Json,
+};
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+use crate::dispatcher::DispatchError;
+use crate::history::Outcome;
+
+/// Rendered as `{"error": code, "message", "retry_after", ..details}`, with
+/// `retry_after` left out when there is none. `code` is stable for
+/// integrators to match on, e.g. `unknown_action` (404, with `action`) or
+/// `forbidden_project` (403, with `project` and `action`).
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    retry_after: Option<u64>,
+    details: BTreeMap<&'static str, String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            retry_after: None,
+            details: BTreeMap::new(),
+        }
+    }
+
+    /// Extra fields alongside `code` and `message` identifying what the
+    /// error is about.
+    pub fn with_detail(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.details.insert(key, value.into());
+        self
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn unauthorized() -> Self {
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "request could not be authenticated",
+        )
+    }
+
+    pub fn not_found() -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", "no such endpoint")
+    }
+
+    pub fn unknown_action(action: &str) -> Self {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown_action",
+            "action is not a known action",
+        )
+        .with_detail("action", action)
+    }
+
+    pub fn payload_too_large() -> Self {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "request body exceeds the maximum allowed size",
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    #[serde(flatten)]
+    details: &'a BTreeMap<&'static str, String>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let body = ErrorBody {
+            error: self.code,
+            message: &self.message,
+            retry_after: self.retry_after,
+            details: &self.details,
+        };
+
+        let mut res = (self.status, Json(body)).into_response();
+        res.extensions_mut().insert(Outcome(self.code));
+        if let Some(secs) = self.retry_after {
+            res.headers_mut()
+                .insert(http::header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res
+    }
+}
+
+/// For the timeout and load shedding layers in front of the webhook routes.
+pub async fn overloaded(err: BoxError) -> ApiError {
+    if err.is::<tower::timeout::error::Elapsed>() {
+        ApiError::new(
+            StatusCode::REQUEST_TIMEOUT,
+            "request_timeout",
+            "request took too long to handle",
+        )
+    } else if err.is::<tower::load_shed::error::Overloaded>() {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "too many requests are being handled",
+        )
+        .with_retry_after(1)
+    } else {
+        tracing::error!("unhandled error handling request: {err}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "request failed",
+        )
+    }
+}
+
+impl From<DispatchError> for ApiError {
+    fn from(err: DispatchError) -> Self {
+        match err {
+            DispatchError::BadPipe | DispatchError::WriterStopped => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "dispatch_failed",
+                "unable to write command to dispatch pipe",
+            ),
+            DispatchError::PipeNotFound => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "pipe_not_found",
+                "dispatch pipe does not exist, check the configured path",
+            ),
+            DispatchError::PipePermission => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "pipe_permission_denied",
+                "server is not permitted to open the dispatch pipe",
+            ),
+            DispatchError::PipeBroken => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pipe_broken",
+                "dispatch closed the pipe, it may be restarting",
+            )
+            .with_retry_after(1),
+            DispatchError::Timeout => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "dispatch_timeout",
+                "timed out writing command to dispatch pipe",
+            )
+            .with_retry_after(1),
+            DispatchError::Overloaded => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "dispatch_overloaded",
+                "too many commands are waiting to be written to the dispatch pipe",
+            )
+            .with_retry_after(1),
+            DispatchError::ProjectForbidden { project, action } => ApiError::new(
+                StatusCode::FORBIDDEN,
+                "forbidden_project",
+                "client is not permitted to run this action for the project",
+            )
+            .with_detail("project", project)
+            .with_detail("action", action.to_string()),
+            DispatchError::ProjectRequired => ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "project_required",
+                "client has several projects, name one in the X-Deploy-Project header",
+            ),
+            DispatchError::WaitUnsupported => ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "wait_unsupported",
+                "waiting for the script needs the unix socket transport",
+            ),
+            DispatchError::ScriptFailed { exit_code, stderr } => {
+                let error = ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "script_failed",
+                    "script did not exit successfully",
+                )
+                .with_detail("stderr", stderr);
+                match exit_code {
+                    Some(code) => error.with_detail("exit_code", code.to_string()),
+                    None => error,
+                }
+            }
+            DispatchError::JournalFailed => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "journal_failed",
+                "unable to record command in the dispatch journal",
+            ),
+            DispatchError::EnvironmentNotAllowed => ApiError::new(
+                StatusCode::FORBIDDEN,
+                "environment_not_allowed",
+                "client may not deploy to the requested environment",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    async fn body_json(res: axum::response::Response) -> Value {
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn timeout_body() {
+        let res = ApiError::from(DispatchError::Timeout).into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[http::header::RETRY_AFTER], "1");
+        assert_eq!(
+            body_json(res).await,
+            json!({
+                "error": "dispatch_timeout",
+                "message": "timed out writing command to dispatch pipe",
+                "retry_after": 1,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn unauthorized_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        let req = Request::post("/deploy")
+            .header("Authorization", "Bearer abc")
+            .header("X-Hub-Signature-256", "sha256=00")
+            .body(Body::from("{}"))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            body_json(res).await,
+            json!({
+                "error": "unauthorized",
+                "message": "request could not be authenticated",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn pipe_errors_map_to_their_status() {
+        use std::io::ErrorKind;
+
+        for (kind, status, error) in [
+            (
+                ErrorKind::NotFound,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "pipe_not_found",
+            ),
+            (
+                ErrorKind::PermissionDenied,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "pipe_permission_denied",
+            ),
+            (
+                ErrorKind::BrokenPipe,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pipe_broken",
+            ),
+            (
+                ErrorKind::ConnectionRefused,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pipe_broken",
+            ),
+            (
+                ErrorKind::ConnectionReset,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pipe_broken",
+            ),
+            (
+                ErrorKind::UnexpectedEof,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "pipe_broken",
+            ),
+            (
+                ErrorKind::InvalidInput,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "dispatch_failed",
+            ),
+        ] {
+            let err = DispatchError::from(std::io::Error::from(kind));
+            let res = ApiError::from(err).into_response();
+            assert_eq!(res.status(), status, "{kind:?}");
+            assert_eq!(body_json(res).await["error"], error, "{kind:?}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Serialize;
+
+use config::{Transport, WebHookConfig};
+
+use crate::reload::Current;
+
+#[derive(Serialize)]
+struct Health<'a> {
+    status: &'static str,
+    pipe: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Healthy while the dispatch pipe can be opened for writing. The open is non
+/// blocking, so a fifo with no reader fails straight away instead of hanging.
+pub async fn healthz(Extension(current): Extension<Arc<Current>>) -> impl IntoResponse {
+    let webhooks = &current.load().config.webhooks;
+    let pipe = &webhooks.pipe;
+
+    let (status, health) = match open_pipe(webhooks) {
+        Ok(_) => (
+            StatusCode::OK,
+            Health {
+                status: "ok",
+                pipe,
+                error: None,
+            },
+        ),
+        Err(err) => {
+            tracing::warn!("health check unable to open {}: {err}", pipe.display());
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Health {
+                    status: "unavailable",
+                    pipe,
+                    error: Some(err.to_string()),
+                },
+            )
+        }
+    };
+
+    (status, Json(health)).into_response()
+}
+
+/// Opens and closes the dispatch pipe, without blocking on a missing reader.
+pub fn open_pipe(webhooks: &WebHookConfig) -> std::io::Result<()> {
+    match webhooks.transport {
+        Transport::Fifo => std::fs::OpenOptions::new()
+            .append(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&webhooks.pipe)
+            .map(drop),
+        Transport::Unix => UnixStream::connect(&webhooks.pipe).map(drop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    async fn healthz(dir: &Path) -> (StatusCode, serde_json::Value) {
+        let app = test_support::app(CONFIG, dir);
+        let req = Request::get("/healthz").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn fifo_with_a_reader_is_healthy() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipe = dir.path().join("pipe");
+        test_support::mkfifo(&pipe);
+        let _reader = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&pipe)
+            .unwrap();
+
+        let (status, health) = healthz(dir.path()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(health["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn missing_pipe_is_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (status, health) = healthz(dir.path()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health["status"], "unavailable");
+        assert!(health["error"].is_string());
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+    Extension, Json,
+};
+use serde::Serialize;
+
+use config::Config;
+
+use crate::auth::{AuthDecision, Authed, GITHUB_DELIVERY_HEADER};
+use crate::error::ApiError;
+use crate::reload::Current;
+use crate::trigger;
+
+/// What became of a request, put in the response extensions by whatever
+/// decided it so the history can say more than the status code.
+#[derive(Debug, Copy, Clone)]
+pub struct Outcome(pub &'static str);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryRecord {
+    /// Seconds since the unix epoch the request arrived at.
+    timestamp: u64,
+    delivery_id: Option<String>,
+    client: Option<String>,
+    action: Option<String>,
+    status: u16,
+    result: &'static str,
+}
+
+/// The most recent webhook requests, oldest first, kept across reloads.
+#[derive(Default)]
+pub struct DeliveryHistory {
+    records: Mutex<VecDeque<DeliveryRecord>>,
+}
+
+impl DeliveryHistory {
+    /// Only the newest `capacity` records are kept, 0 keeps none.
+    fn push(&self, record: DeliveryRecord, capacity: usize) {
+        let mut records = self.records.lock().unwrap();
+        records.push_back(record);
+        while records.len() > capacity {
+            records.pop_front();
+        }
+    }
+
+    fn recent(&self) -> Vec<DeliveryRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Records every webhook request once it has been answered.
+pub async fn record(req: Request<Body>, next: Next<Body>) -> Response {
+    let capacity = req
+        .extensions()
+        .get::<&'static Config>()
+        .map_or(0, |config| config.webhooks.delivery_history_size);
+    let history = match req.extensions().get::<Arc<DeliveryHistory>>() {
+        Some(history) if capacity > 0 => history.clone(),
+        _ => return next.run(req).await,
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let delivery_id = req
+        .headers()
+        .get(&GITHUB_DELIVERY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let action = action(req.uri().path());
+
+    let res = next.run(req).await;
+
+    let client = match res.extensions().get::<AuthDecision>() {
+        Some(AuthDecision::Authed(Authed(client))) => Some(client.name.clone()),
+        _ => None,
+    };
+    let result = match res.extensions().get::<Outcome>() {
+        Some(Outcome(result)) => result,
+        None if res.status().is_success() => "ok",
+        None => "failed",
+    };
+
+    history.push(
+        DeliveryRecord {
+            timestamp,
+            delivery_id,
+            client,
+            action,
+            status: res.status().as_u16(),
+            result,
+        },
+        capacity,
+    );
+    res
+}
+
+/// `/deploy/staging` is a deploy, `/action/rollback` a rollback.
+fn action(path: &str) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("action"), Some(action)) => Some(action.to_string()),
+        (Some(action), _) if !action.is_empty() => Some(action.to_string()),
+        _ => None,
+    }
+}
+
+/// The recorded requests as a JSON list, oldest first, for an operator
+/// holding the `admin_secret`.
+pub async fn deliveries(
+    Extension(current): Extension<Arc<Current>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DeliveryRecord>>, ApiError> {
+    trigger::authorize_admin(current.load().config, &headers)?;
+    Ok(Json(current.history().recent()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    fn with_delivery(mut req: Request<Body>, delivery_id: &str) -> Request<Body> {
+        req.headers_mut()
+            .insert(&GITHUB_DELIVERY_HEADER, delivery_id.parse().unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_listed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let toml = CONFIG.replace(
+            "[dispatch]",
+            "admin_secret = \"adm1n\"\ndelivery_history_size = 2\n\n[dispatch]",
+        );
+        let app = test_support::app(&toml, dir.path());
+
+        let mut unsigned = test_support::signed("/deploy", "{}");
+        unsigned.headers_mut().remove("X-Hub-Signature-256");
+        for (delivery_id, req) in [
+            ("d-1", test_support::signed("/deploy", "{}")),
+            ("d-2", test_support::signed("/deploy", "{}")),
+            ("d-3", unsigned),
+        ] {
+            app.clone()
+                .oneshot(with_delivery(req, delivery_id))
+                .await
+                .unwrap();
+        }
+        assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let req = Request::get("/deliveries")
+            .header("Authorization", "Bearer adm1n")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let records: Vec<Value> = serde_json::from_slice(&body).unwrap();
+
+        // the oldest has made room for the newest
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    record["delivery_id"].as_str().unwrap(),
+                    record["client"].as_str(),
+                    record["action"].as_str().unwrap(),
+                    record["status"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("d-2", Some("abc"), "deploy", 200),
+                ("d-3", None, "deploy", 401),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn deliveries_need_the_admin_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "admin_secret = \"adm1n\"\n\n[dispatch]");
+        let app = test_support::app(&toml, dir.path());
+
+        let req = Request::get("/deliveries")
+            .header("Authorization", "Bearer abc")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
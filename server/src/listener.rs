@@ -0,0 +1,201 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::ConnectInfo,
+    http::{Request, Response},
+    BoxError, Router,
+};
+use axum_server::accept::Accept;
+use hyper::server::conn::Http;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tower::Service;
+use tower_http::add_extension::AddExtension;
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts connections and serves `app` on each, with `max_connections`
+/// bounding how many are open at once. At the limit new connections are left
+/// in the listen backlog until an open one closes.
+///
+/// Once `shutdown` completes no more connections are accepted, open ones are
+/// told to finish their current request and close, and up to `grace` is
+/// given for them to do so.
+pub async fn serve<A, S, B>(
+    addr: SocketAddr,
+    acceptor: A,
+    app: Router,
+    max_connections: Option<usize>,
+    shutdown: impl Future<Output = ()>,
+    grace: Duration,
+) -> std::io::Result<()>
+where
+    A: Accept<TcpStream, AddExtension<Router, ConnectInfo<SocketAddr>>, Service = S>
+        + Clone
+        + Send
+        + 'static,
+    A::Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    A::Future: Send,
+    S: Service<Request<Body>, Response = Response<B>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let limit = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+
+    let (closing, closing_rx) = watch::channel(false);
+    // every connection task holds a sender, recv sees None once they have all finished
+    let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+    let open = Arc::new(AtomicUsize::new(0));
+
+    tokio::pin!(shutdown);
+    loop {
+        let permit = tokio::select! {
+            _ = &mut shutdown => break,
+            permit = acquire(limit.as_ref()) => permit,
+        };
+
+        let accepted = tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => accepted,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // usually running out of file descriptors, give some a chance to close
+                tracing::warn!("unable to accept connection: {err}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let app = AddExtension::new(app.clone(), ConnectInfo(peer));
+        let mut closing = closing_rx.clone();
+        let open_tx = open_tx.clone();
+        let open = open.clone();
+        open.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _open_tx = open_tx;
+
+            if let Ok((stream, service)) = acceptor.accept(stream, app).await {
+                let conn = Http::new().serve_connection(stream, service);
+                tokio::pin!(conn);
+
+                let result = tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = closed(&mut closing) => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(err) = result {
+                    tracing::debug!("connection closed with error: {err}");
+                }
+            } else {
+                tracing::debug!("unable to establish connection");
+            }
+
+            open.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    drop(listener);
+    tracing::info!(
+        "shutting down with {} connections open",
+        open.load(Ordering::Relaxed)
+    );
+
+    let _ = closing.send(true);
+    drop(open_tx);
+    if timeout(grace, open_rx.recv()).await.is_err() {
+        tracing::warn!(
+            "closing {} connections still open after {grace:?}",
+            open.load(Ordering::Relaxed)
+        );
+    }
+
+    Ok(())
+}
+
+async fn closed(closing: &mut watch::Receiver<bool>) {
+    let _ = closing.wait_for(|closing| *closing).await;
+}
+
+async fn acquire(limit: Option<&Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match limit {
+        Some(limit) => Some(limit.clone().acquire_owned().await.unwrap()),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum_server::accept::DefaultAcceptor;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    async fn get_root(addr: SocketAddr) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        Ok(res)
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_once_shutdown_fires() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let served = tokio::spawn(serve(
+            addr,
+            DefaultAcceptor,
+            app,
+            None,
+            async move {
+                let _ = shutdown_rx.await;
+            },
+            Duration::from_secs(1),
+        ));
+
+        let mut res = get_root(addr).await;
+        for _ in 0..50 {
+            if res.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            res = get_root(addr).await;
+        }
+        assert!(res.unwrap().ends_with("ok"));
+
+        shutdown.send(()).unwrap();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .expect("serve returns after shutdown")
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -1,235 +1,432 @@
+mod auth;
+mod body;
+mod capture;
+mod clock;
+mod dispatcher;
+mod error;
+mod health;
+mod history;
+mod listener;
+mod metrics;
+mod rate_limit;
+mod reload;
+mod replay;
+mod request_id;
+mod signature;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tls;
+mod trigger;
+mod validate;
+
+#[cfg(test)]
+mod test_support;
+
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use axum::{
-    body::Body,
-    extract::{FromRequest, RequestParts},
-    http::{self, Request},
-    middleware::Next,
-    response::IntoResponse,
-    routing::post,
+    body::Bytes,
+    error_handling::HandleErrorLayer,
+    extract::{FromRequest, Query, RequestParts},
+    handler::Handler,
+    http::{self, HeaderMap, StatusCode},
+    routing::{get, post},
     Extension, Router,
 };
-use hmac_sha256::HMAC;
-use serde::Deserialize;
-use tokio::{fs::File, io::AsyncWriteExt, time::timeout};
+use axum_server::accept::DefaultAcceptor;
+use tokio::signal::unix::{signal, SignalKind};
+use tower::limit::ConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
+use tower::timeout::TimeoutLayer;
 use tower::ServiceBuilder;
+use tower_http::request_id::{PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
 
-use clap::Parser;
+use config::logging::FileLogHandle;
+use config::startup::StartupError;
+use config::{Action, Config};
 
-use config::{Action, ClientConfig, Config};
+use auth::Authed;
+use dispatcher::{Dispatched, Dispatcher};
+use error::ApiError;
 
 #[derive(Parser)]
 struct Args {
-    #[clap(long, default_value = "config.toml")]
+    #[clap(long, default_value = "config.toml", global = true)]
     config: PathBuf,
+    /// Print the config with defaults applied and secrets redacted, then exit
+    #[clap(long)]
+    print_effective_config: bool,
+    /// Print a JSON summary of the clients, their scripts and the dispatch
+    /// pipe, then exit
+    #[clap(long)]
+    check: bool,
+    /// OTLP/HTTP traces endpoint to export spans to
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+    /// Runs the server when absent
+    #[clap(subcommand)]
+    command: Option<ServerCommand>,
+}
+
+#[derive(Subcommand)]
+enum ServerCommand {
+    /// Check the config and the scripts and TLS files it refers to, then exit
+    Validate,
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
-
     let args = Args::parse();
 
-    tracing::info!("loading config from: {}", args.config.display());
-
-    let config_file = tokio::fs::read_to_string(args.config).await.unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    let config: &'static Config = Box::leak(Box::new(config));
-
-    let dispatcher = Arc::new(Dispatcher::new(config.webhooks.pipe.clone()));
-
-    let layers = ServiceBuilder::new()
-        .layer(Extension(config))
-        .layer(Extension(dispatcher))
-        .layer(TraceLayer::new_for_http())
-        .layer(axum::middleware::from_fn(validate_signature));
-
-    let app = Router::new().route("/deploy", post(deploy)).layer(layers);
+    let (file_log, file_log_handle) = config::logging::file_layer();
+    let subscriber = tracing_subscriber::registry()
+        .with(file_log)
+        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer());
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(args.otlp_endpoint.as_deref().map(|endpoint| {
+        telemetry::layer(endpoint).unwrap_or_else(|err| {
+            eprintln!("unable to configure otlp exporter: {err}");
+            std::process::exit(1);
+        })
+    }));
+    subscriber.init();
+
+    if let Err(err) = run(args, file_log_handle).await {
+        tracing::error!("{err}");
+        std::process::exit(err.exit_code());
+    }
 
-    let addr =
-        std::net::SocketAddr::from((config.webhooks.listen_addr, config.webhooks.listen_port));
-    tracing::info!("listening on: {addr}");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap()
+    // a pipe open abandoned after timing out stays blocked on a runtime thread
+    // until a reader appears, exit rather than let the runtime wait on it
+    std::process::exit(0);
 }
 
-const SIGNATURE_HEADER: http::header::HeaderName =
-    http::header::HeaderName::from_static("x-hub-signature-256");
+async fn run(args: Args, file_log: FileLogHandle) -> Result<(), StartupError> {
+    if let Some(ServerCommand::Validate) = args.command {
+        return validate::validate(&args.config);
+    }
+    if args.check {
+        return validate::check(&args.config);
+    }
 
-#[derive(Debug, Copy, Clone)]
-struct Authed<'a>(&'a ClientConfig);
+    tracing::info!("loading config from: {}", args.config.display());
 
-#[derive(Debug, Deserialize)]
-struct Deploy;
+    let config = Config::load(&args.config)?;
+    if args.print_effective_config {
+        print!("{}", config.to_effective_toml());
+        return Ok(());
+    }
+    config::logging::set_file_log(&file_log, &config.webhooks.log)
+        .map_err(|err| StartupError::Other(format!("unable to open log file: {err}")))?;
+
+    for (name, client) in config.clients.iter() {
+        tracing::debug!(
+            "client {name} secret fingerprint: {}",
+            signature::secret_fingerprint(&client.secret)
+        );
+    }
 
-async fn validate_signature(req: Request<Body>, next: Next<Body>) -> impl IntoResponse {
-    let config = req.extensions().get::<&'static Config>().cloned();
+    validate::SelfCheck::run(&config).log();
 
-    let has_sig = req.headers().contains_key(&SIGNATURE_HEADER);
+    let current = Arc::new(reload::Current::new(args.config.clone(), config));
+    reload::reload_on_sighup(current.clone())
+        .map_err(|err| StartupError::Other(format!("unable to handle SIGHUP: {err}")))?;
+    let config = current.load().config;
 
-    for (name, value) in req.headers().iter() {
-        tracing::trace!("Header: {}={}", name.as_str(), value.to_str().unwrap_or(""));
-    }
+    let app = app(current);
 
-    let client = req
-        .headers()
-        .get(http::header::AUTHORIZATION)
-        .and_then(|v| if has_sig { Some(v) } else { None })
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Basic "))
-        .and_then(|v| base64::decode(v.as_bytes()).ok())
-        .and_then(|v| String::from_utf8(v).ok())
-        .zip(config)
-        .and_then(|(client, config)| {
-            let client_key = client.strip_suffix(":").unwrap_or(&client);
-            config.clients.get(client_key)
-        });
-
-    let client = if let Some(client) = client {
-        client
+    let addr =
+        std::net::SocketAddr::from((config.webhooks.listen_addr, config.webhooks.listen_port));
+    let tls = tls::rustls_config(&config.webhooks).map_err(|err| {
+        StartupError::ConfigValidation(format!("unable to load tls configuration: {err}"))
+    })?;
+
+    let max_connections = config.webhooks.max_connections;
+    let grace = Duration::from_secs(config.webhooks.shutdown_grace_secs);
+    let shutdown = shutdown_signal()
+        .map_err(|err| StartupError::Other(format!("unable to handle SIGTERM: {err}")))?;
+    let served = if let Some(tls) = tls {
+        tracing::info!("listening with tls on: {addr}");
+        let acceptor = tls::PeerNamesAcceptor::new(tls);
+        listener::serve(addr, acceptor, app, max_connections, shutdown, grace).await
     } else {
-        tracing::info!("webhook request missing required headers");
-        return next.run(req).await;
+        tracing::info!("listening on: {addr}");
+        listener::serve(addr, DefaultAcceptor, app, max_connections, shutdown, grace).await
     };
+    served.map_err(|err| StartupError::Bind(format!("unable to listen on {addr}: {err}")))
+}
 
-    let (parts, body) = req.into_parts();
+/// The webhook routes behind their layers, and the routes that need none.
+fn app(current: Arc<reload::Current>) -> Router {
+    let config = current.load().config;
 
-    let bytes = match hyper::body::to_bytes(body).await {
-        Ok(bytes) => bytes,
-        Err(_err) => {
-            tracing::warn!("unable to read webhook body");
-            let req = Request::from_parts(parts, Body::empty());
-            return next.run(req).await;
-        }
-    };
-
-    tracing::trace!("read body, got {} bytes", bytes.len());
-    tracing::trace!("{}", String::from_utf8_lossy(&bytes));
+    let layers = ServiceBuilder::new()
+        .layer(Extension(current.clone()))
+        .layer(axum::middleware::from_fn(reload::snapshot))
+        .layer(SetRequestIdLayer::new(
+            request_id::REQUEST_ID_HEADER,
+            request_id::DeliveryOrUuid,
+        ))
+        .layer(PropagateRequestIdLayer::new(request_id::REQUEST_ID_HEADER))
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_span))
+        .layer(HandleErrorLayer::new(error::overloaded))
+        .option_layer(config.webhooks.max_concurrent_requests.map(|max| {
+            ServiceBuilder::new()
+                .layer(LoadShedLayer::new())
+                .layer(ConcurrencyLimitLayer::new(max))
+        }))
+        .option_layer(
+            config
+                .webhooks
+                .request_timeout_secs
+                .map(|secs| TimeoutLayer::new(Duration::from_secs(secs))),
+        )
+        .layer(axum::middleware::from_fn(capture::capture_request))
+        .layer(axum::middleware::from_fn(history::record))
+        .layer(axum::middleware::from_fn(auth::validate_signature));
+
+    Router::new()
+        .route("/deploy", post(dispatch_action))
+        .route("/deploy/:environment", post(dispatch_action))
+        .route("/rollback", post(dispatch_action))
+        .route("/restart", post(dispatch_action))
+        .route("/action/:action", post(dispatch_action))
+        .layer(layers)
+        .route("/deploy", get(preflight))
+        .route("/deploy/:environment", get(preflight))
+        .route("/rollback", get(preflight))
+        .route("/restart", get(preflight))
+        .route("/action/:action", get(preflight))
+        .route("/metrics", get(metrics::metrics))
+        .route(
+            "/healthz",
+            get(health::healthz).layer(Extension(current.clone())),
+        )
+        .route(
+            "/trigger",
+            post(trigger::trigger).layer(Extension(current.clone())),
+        )
+        .route(
+            "/deliveries",
+            get(history::deliveries).layer(Extension(current.clone())),
+        )
+        .fallback(not_found.into_service())
+}
 
-    let hmac = HMAC::mac(&bytes, client.secret.as_bytes());
+/// Completes on the first SIGTERM or ctrl-c.
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!("received SIGTERM"),
+            _ = interrupt.recv() => tracing::info!("received SIGINT"),
+        }
+    })
+}
 
-    use std::fmt::Write;
-    let expected_signature = hmac
-        .into_iter()
-        .fold(String::from("sha256="), |mut acc, n| {
-            let _ = write!(acc, "{:02x}", n);
-            acc
-        });
+/// `?wait=true` holds the response until the script has finished.
+#[derive(Deserialize)]
+struct WaitQuery {
+    #[serde(default)]
+    wait: bool,
+}
 
-    let mut req = Request::from_parts(parts, bytes.into());
+const ENVIRONMENT_HEADER: http::header::HeaderName =
+    http::header::HeaderName::from_static("x-deploy-environment");
 
-    let signature = req
-        .headers()
-        .get(&SIGNATURE_HEADER)
-        .and_then(|s| s.to_str().ok());
+/// The action and environment a webhook path names: `/deploy`,
+/// `/deploy/staging`, `/rollback` or `/action/restart`. Each route serves
+/// the one handler, an action the path names that does not exist is a 404.
+struct ActionPath {
+    action: Action,
+    environment: Option<String>,
+}
 
-    tracing::trace!("expected signature: {}", expected_signature);
-    if let Some(sig) = signature.as_ref() {
-        tracing::trace!("provided signature: {}", sig);
-    } else {
-        tracing::trace!("no signature provided");
-    }
+#[async_trait::async_trait]
+impl<B: Send> FromRequest<B> for ActionPath {
+    type Rejection = ApiError;
 
-    if signature == Some(expected_signature.as_str()) {
-        tracing::info!("webhook request authenticated");
-        req.extensions_mut().insert(Authed(client));
-    } else {
-        tracing::info!("webhook request unable to be authenticated");
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let mut segments = req.uri().path().trim_start_matches('/').split('/');
+        let (action, environment) = match (segments.next(), segments.next()) {
+            (Some("action"), Some(action)) => (action, None),
+            (Some(action), environment) => (action, environment.map(String::from)),
+            (None, _) => return Err(ApiError::not_found()),
+        };
+        let action = action
+            .parse()
+            .map_err(|_| ApiError::unknown_action(action))?;
+
+        Ok(ActionPath {
+            action,
+            environment,
+        })
     }
-
-    next.run(req).await
 }
 
-async fn deploy(
+#[tracing::instrument(skip_all)]
+async fn dispatch_action(
     auth: Authed<'static>,
+    ActionPath {
+        action,
+        environment,
+    }: ActionPath,
     Extension(dispatcher): Extension<Arc<Dispatcher>>,
-) -> impl IntoResponse {
-    tracing::info!("received deploy request");
-    dispatcher.dispatch(auth, Action::Deploy).await
+    Query(query): Query<WaitQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Dispatched, ApiError> {
+    tracing::info!("received {action} request");
+    let environment = environment.or_else(|| environment_header(&headers));
+    Ok(dispatcher
+        .dispatch(auth, action, environment, &headers, &body, query.wait)
+        .await?)
 }
 
-struct Dispatcher {
-    pipe: PathBuf,
+fn environment_header(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(&ENVIRONMENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
 }
 
-impl Dispatcher {
-    fn new(pipe: PathBuf) -> Self {
-        Dispatcher { pipe }
-    }
+/// Answers the `GET` or `HEAD` some providers send to check a webhook URL is
+/// reachable before delivering to it. Added after the webhook layers, so it
+/// needs no signature and dispatches nothing.
+async fn preflight() -> StatusCode {
+    StatusCode::OK
+}
 
-    async fn dispatch(
-        &self,
-        Authed(client): Authed<'static>,
-        action: Action,
-    ) -> Result<(), DispatchError> {
-        if client.permissions.contains(&action) {
-            let dispatch = async {
-                let cmd = config::Command {
-                    action,
-                    project: client.project.clone(),
-                };
-
-                tracing::info!("dispatching: {}", cmd);
-
-                let mut pipe: File = tokio::fs::OpenOptions::new()
-                    .append(true)
-                    .open(&self.pipe)
-                    .await?;
-                pipe.write_all(format!("{}\n", cmd).as_bytes()).await?;
-                pipe.flush().await?;
-
-                Ok(())
-            };
-
-            timeout(Duration::from_secs(1), dispatch)
+async fn not_found() -> ApiError {
+    ApiError::not_found()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    #[tokio::test]
+    async fn action_path_dispatches_its_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        for uri in ["/action/deploy", "/deploy"] {
+            let res = app
+                .clone()
+                .oneshot(test_support::signed(uri, "{}"))
                 .await
-                .map_err(|_| DispatchError::Timeout)?
-        } else {
-            Ok(())
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+            let line = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+            let command = config::Command::from_wire(&line).unwrap();
+            assert_eq!(command.to_string(), "deploy proj", "{uri}");
         }
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-enum DispatchError {
-    BadPipe,
-    Timeout,
-}
+    #[tokio::test]
+    async fn action_without_permission_is_forbidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
 
-impl std::error::Error for DispatchError {}
+        let res = app
+            .oneshot(test_support::signed("/action/rollback", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
 
-impl std::fmt::Display for DispatchError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+    #[tokio::test]
+    async fn unknown_action_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(CONFIG, dir.path());
+
+        for uri in ["/action/explode", "/explode"] {
+            let res = app
+                .clone()
+                .oneshot(test_support::signed(uri, "{}"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
     }
-}
 
-impl IntoResponse for DispatchError {
-    fn into_response(self) -> axum::response::Response {
-        http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    /// A signed request whose body never finishes arriving, until the sender
+    /// is dropped.
+    fn trickling() -> (Body, hyper::body::Sender) {
+        let (sender, body) = Body::channel();
+        (body, sender)
     }
-}
 
-impl From<std::io::Error> for DispatchError {
-    fn from(_: std::io::Error) -> Self {
-        DispatchError::BadPipe
+    #[tokio::test]
+    async fn request_past_the_timeout_is_answered_408() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "request_timeout_secs = 1\n\n[dispatch]");
+        let app = test_support::app(&toml, dir.path());
+
+        let (body, _sender) = trickling();
+        let mut req = test_support::signed("/deploy", "{}");
+        *req.body_mut() = body;
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
     }
-}
 
-#[async_trait::async_trait]
-impl<B: Send> FromRequest<B> for Authed<'_> {
-    type Rejection = http::StatusCode;
+    #[tokio::test]
+    async fn request_past_the_concurrency_cap_is_shed() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = CONFIG.replace("[dispatch]", "max_concurrent_requests = 1\n\n[dispatch]");
+        let app = test_support::app(&toml, dir.path());
+
+        let (body, _sender) = trickling();
+        let mut stuck = test_support::signed("/deploy", "{}");
+        *stuck.body_mut() = body;
+        let stuck = tokio::spawn(app.clone().oneshot(stuck));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let res = app
+            .oneshot(test_support::signed("/deploy", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        stuck.abort();
+    }
 
-    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        if let Some(authed) = req.extensions().get::<Authed>() {
-            Ok(authed.clone())
-        } else {
-            Err(http::StatusCode::UNAUTHORIZED)
+    #[tokio::test]
+    async fn preflight_is_answered_without_dispatching() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        for method in [http::Method::GET, http::Method::HEAD] {
+            let req = Request::builder()
+                .method(method.clone())
+                .uri("/deploy")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "{method}");
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert!(body.is_empty());
         }
+        assert!(lines.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let req = Request::post("/deploy").body(Body::from("{}")).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
     }
 }
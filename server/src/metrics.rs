@@ -0,0 +1,351 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use config::Action;
+
+use crate::dispatcher::DispatchError;
+
+const DISPATCH_ERRORS: [&str; 7] = [
+    "bad_pipe",
+    "timeout",
+    "overloaded",
+    "pipe_not_found",
+    "pipe_permission",
+    "pipe_broken",
+    "writer_stopped",
+];
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    requests: AtomicU64,
+    authenticated: AtomicU64,
+    rejected: AtomicU64,
+    unverified: AtomicU64,
+    body_bytes_read: AtomicU64,
+    dispatches: [AtomicU64; Action::ALL.len()],
+    dispatch_errors: [AtomicU64; DISPATCH_ERRORS.len()],
+    dispatch_bytes_written: AtomicU64,
+    dispatch_latency: Histogram,
+}
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            requests: AtomicU64::new(0),
+            authenticated: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            unverified: AtomicU64::new(0),
+            body_bytes_read: AtomicU64::new(0),
+            dispatches: [const { AtomicU64::new(0) }; Action::ALL.len()],
+            dispatch_errors: [const { AtomicU64::new(0) }; DISPATCH_ERRORS.len()],
+            dispatch_bytes_written: AtomicU64::new(0),
+            dispatch_latency: Histogram {
+                buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+                sum_micros: AtomicU64::new(0),
+                count: AtomicU64::new(0),
+            },
+        }
+    }
+
+    pub fn request_received(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn authenticated(&self, authenticated: bool) {
+        let counter = if authenticated {
+            &self.authenticated
+        } else {
+            &self.rejected
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request answered from its headers before its signature was checked.
+    pub fn unverified(&self) {
+        self.unverified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn body_read(&self, bytes: usize) {
+        self.body_bytes_read
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a finished attempt to write a command, successful or not.
+    pub fn dispatched(
+        &self,
+        action: Action,
+        result: &Result<(), DispatchError>,
+        latency: Duration,
+    ) {
+        match result {
+            Ok(()) => {
+                let idx = Action::ALL.iter().position(|a| *a == action).unwrap_or(0);
+                self.dispatches[idx].fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                // only pipe write failures get this far
+                let idx = match err {
+                    DispatchError::Timeout => 1,
+                    DispatchError::Overloaded => 2,
+                    DispatchError::PipeNotFound => 3,
+                    DispatchError::PipePermission => 4,
+                    DispatchError::PipeBroken => 5,
+                    DispatchError::WriterStopped => 6,
+                    _ => 0,
+                };
+                self.dispatch_errors[idx].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let latency_secs = latency.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.dispatch_latency.buckets) {
+            if latency_secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.dispatch_latency
+            .sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.dispatch_latency.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dispatch_written(&self, bytes: usize) {
+        self.dispatch_bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "webhooks_requests_total",
+            "Webhook requests received",
+            &self.requests,
+        );
+        labeled_counter(
+            &mut out,
+            "webhooks_auth_total",
+            "Webhook requests by authentication outcome",
+            "result",
+            [
+                ("authenticated", &self.authenticated),
+                ("rejected", &self.rejected),
+                ("unverified", &self.unverified),
+            ],
+        );
+        counter(
+            &mut out,
+            "webhooks_body_bytes_read_total",
+            "Bytes read from webhook request bodies",
+            &self.body_bytes_read,
+        );
+        let actions: Vec<_> = Action::ALL.iter().map(|a| a.to_string()).collect();
+        labeled_counter(
+            &mut out,
+            "webhooks_dispatches_total",
+            "Commands written to dispatch pipes by action",
+            "action",
+            actions.iter().map(String::as_str).zip(&self.dispatches),
+        );
+        labeled_counter(
+            &mut out,
+            "webhooks_dispatch_errors_total",
+            "Commands that could not be written to a dispatch pipe",
+            "error",
+            DISPATCH_ERRORS.into_iter().zip(&self.dispatch_errors),
+        );
+        counter(
+            &mut out,
+            "webhooks_dispatch_bytes_written_total",
+            "Bytes of commands written to dispatch pipes",
+            &self.dispatch_bytes_written,
+        );
+        histogram(
+            &mut out,
+            "webhooks_dispatch_latency_seconds",
+            "Time taken to write a command to a dispatch pipe",
+            &self.dispatch_latency,
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+}
+
+fn labeled_counter<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl IntoIterator<Item = (&'a str, &'a AtomicU64)>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (value, count) in values {
+        let _ = writeln!(
+            out,
+            "{name}{{{label}=\"{value}\"}} {}",
+            count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{le=\"{bound}\"}} {}",
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{name}_sum {sum}");
+    let _ = writeln!(out, "{name}_count {count}");
+}
+
+pub async fn metrics() -> String {
+    METRICS.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    /// The value of the exposition line starting with `series`.
+    fn value(rendered: &str, series: &str) -> u64 {
+        rendered
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {series} in {rendered}"))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn render_counts_what_was_recorded() {
+        let metrics = Metrics::new();
+        metrics.request_received();
+        metrics.request_received();
+        metrics.authenticated(true);
+        metrics.authenticated(false);
+        metrics.dispatched(Action::Rollback, &Ok(()), Duration::from_millis(2));
+        metrics.dispatched(
+            Action::Deploy,
+            &Err(DispatchError::Timeout),
+            Duration::from_millis(20),
+        );
+
+        let rendered = metrics.render();
+        assert_eq!(value(&rendered, "webhooks_requests_total"), 2);
+        assert_eq!(
+            value(&rendered, "webhooks_auth_total{result=\"authenticated\"}"),
+            1
+        );
+        assert_eq!(
+            value(&rendered, "webhooks_auth_total{result=\"rejected\"}"),
+            1
+        );
+        assert_eq!(
+            value(&rendered, "webhooks_dispatches_total{action=\"rollback\"}"),
+            1
+        );
+        assert_eq!(
+            value(&rendered, "webhooks_dispatches_total{action=\"deploy\"}"),
+            0
+        );
+        assert_eq!(
+            value(
+                &rendered,
+                "webhooks_dispatch_errors_total{error=\"timeout\"}"
+            ),
+            1
+        );
+        assert_eq!(
+            value(
+                &rendered,
+                "webhooks_dispatch_latency_seconds_bucket{le=\"0.005\"}"
+            ),
+            1
+        );
+        assert_eq!(
+            value(&rendered, "webhooks_dispatch_latency_seconds_count"),
+            2
+        );
+    }
+
+    #[test]
+    fn each_pipe_failure_has_its_own_label() {
+        let metrics = Metrics::new();
+        for err in [
+            DispatchError::BadPipe,
+            DispatchError::PipeNotFound,
+            DispatchError::PipePermission,
+            DispatchError::PipeBroken,
+            DispatchError::WriterStopped,
+        ] {
+            metrics.dispatched(Action::Deploy, &Err(err), Duration::ZERO);
+        }
+
+        let rendered = metrics.render();
+        for label in [
+            "bad_pipe",
+            "pipe_not_found",
+            "pipe_permission",
+            "pipe_broken",
+            "writer_stopped",
+        ] {
+            let series = format!("webhooks_dispatch_errors_total{{error=\"{label}\"}}");
+            assert_eq!(value(&rendered, &series), 1, "{label}");
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoint_reports_requests_handled() {
+        let dir = tempfile::tempdir().unwrap();
+        let _lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        let res = app
+            .clone()
+            .oneshot(test_support::signed("/deploy", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut unsigned = test_support::signed("/deploy", "{}");
+        unsigned.headers_mut().remove("X-Hub-Signature-256");
+        app.clone().oneshot(unsigned).await.unwrap();
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let rendered = String::from_utf8(body.to_vec()).unwrap();
+        // other tests share the counters, so only lower bounds hold
+        assert!(value(&rendered, "webhooks_requests_total") >= 2);
+        assert!(value(&rendered, "webhooks_auth_total{result=\"authenticated\"}") >= 1);
+        assert!(value(&rendered, "webhooks_auth_total{result=\"rejected\"}") >= 1);
+        assert!(value(&rendered, "webhooks_dispatches_total{action=\"deploy\"}") >= 1);
+    }
+}
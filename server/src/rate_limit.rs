@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+
+/// A token bucket per client, refilled continuously at the client's rate.
+pub struct RateLimits {
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimits {
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        RateLimits {
+            clock,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, or says how long until one is
+    /// available. A bucket starts full, holding `burst` tokens.
+    pub fn acquire(
+        &self,
+        client: &str,
+        per_minute: NonZeroU32,
+        burst: u32,
+    ) -> Result<(), Duration> {
+        let per_sec = f64::from(per_minute.get()) / 60.0;
+        let burst = f64::from(burst.max(1));
+
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn burst_is_allowed_then_refills_at_the_rate() {
+        let clock = Arc::new(FakeClock::new());
+        let limits = RateLimits::with_clock(clock.clone());
+        let per_minute = NonZeroU32::new(60).unwrap();
+
+        for _ in 0..3 {
+            assert!(limits.acquire("abc", per_minute, 3).is_ok());
+        }
+        let retry_after = limits.acquire("abc", per_minute, 3).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(limits.acquire("abc", per_minute, 3).is_ok());
+        assert!(limits.acquire("abc", per_minute, 3).is_err());
+    }
+
+    #[test]
+    fn clients_have_their_own_buckets() {
+        let limits = RateLimits::with_clock(Arc::new(FakeClock::new()));
+        let per_minute = NonZeroU32::new(1).unwrap();
+
+        assert!(limits.acquire("abc", per_minute, 1).is_ok());
+        assert!(limits.acquire("abc", per_minute, 1).is_err());
+        assert!(limits.acquire("other", per_minute, 1).is_ok());
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use axum::{body::Body, http::Request, middleware::Next, response::Response};
+use tokio::signal::unix::{signal, SignalKind};
+
+use config::{startup::StartupError, Config};
+
+use crate::dispatcher::Dispatcher;
+use crate::history::DeliveryHistory;
+use crate::rate_limit::RateLimits;
+use crate::replay::SeenDeliveries;
+
+/// A config and the dispatcher built from it, swapped as one so a request
+/// never sees the dispatcher of a different config.
+pub struct Runtime {
+    pub config: &'static Config,
+    pub dispatcher: Arc<Dispatcher>,
+}
+
+impl Runtime {
+    fn new(config: Config) -> Self {
+        // requests still in flight may hold the old config, so replaced configs
+        // are leaked rather than freed, reloads are rare enough for this not to matter
+        let config: &'static Config = Box::leak(Box::new(config));
+        Runtime {
+            config,
+            dispatcher: Arc::new(Dispatcher::new(config)),
+        }
+    }
+}
+
+/// The runtime in use, replaced on SIGHUP. Listener settings, TLS,
+/// `max_connections`, `max_concurrent_requests` and `request_timeout_secs`
+/// are only read at startup and need a restart to change.
+pub struct Current {
+    path: PathBuf,
+    runtime: ArcSwap<Runtime>,
+    deliveries: Arc<SeenDeliveries>,
+    rate_limits: Arc<RateLimits>,
+    history: Arc<DeliveryHistory>,
+}
+
+impl Current {
+    pub fn new(path: PathBuf, config: Config) -> Self {
+        Current {
+            path,
+            runtime: ArcSwap::from_pointee(Runtime::new(config)),
+            deliveries: Arc::default(),
+            rate_limits: Arc::default(),
+            history: Arc::default(),
+        }
+    }
+
+    pub fn history(&self) -> &DeliveryHistory {
+        &self.history
+    }
+
+    pub fn load(&self) -> Arc<Runtime> {
+        self.runtime.load_full()
+    }
+
+    /// Loads and validates the config file, only replacing the current
+    /// runtime if that succeeds.
+    pub fn reload(&self) -> Result<(), StartupError> {
+        let config = Config::load(&self.path)?;
+        self.runtime.store(Arc::new(Runtime::new(config)));
+        Ok(())
+    }
+}
+
+pub fn reload_on_sighup(current: Arc<Current>) -> std::io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            tracing::info!("received SIGHUP, reloading config");
+            match current.reload() {
+                Ok(()) => tracing::info!("config reloaded"),
+                Err(err) => tracing::error!("keeping previous config: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Puts the current config and dispatcher into the request extensions, so
+/// everything after sees one snapshot for the whole request. Seen deliveries,
+/// rate limits and the delivery history are kept across reloads.
+pub async fn snapshot(mut req: Request<Body>, next: Next<Body>) -> Response {
+    let (runtime, deliveries, rate_limits, history) = match req.extensions().get::<Arc<Current>>() {
+        Some(current) => (
+            current.load(),
+            current.deliveries.clone(),
+            current.rate_limits.clone(),
+            current.history.clone(),
+        ),
+        None => return next.run(req).await,
+    };
+
+    req.extensions_mut().insert(runtime.config);
+    req.extensions_mut().insert(runtime.dispatcher.clone());
+    req.extensions_mut().insert(deliveries);
+    req.extensions_mut().insert(rate_limits);
+    req.extensions_mut().insert(history);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    fn write_config(dir: &std::path::Path, toml: &str) {
+        let toml = toml.replace("{dir}", &dir.display().to_string());
+        std::fs::write(dir.join("config.toml"), toml).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloaded_config_takes_effect() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = Current::new(
+            dir.path().join("config.toml"),
+            test_support::parse(CONFIG, dir.path()),
+        );
+        assert_eq!(current.load().config.clients["abc"].secret, "s3cret");
+
+        write_config(dir.path(), &CONFIG.replace("s3cret", "rotated"));
+        current.reload().unwrap();
+        assert_eq!(current.load().config.clients["abc"].secret, "rotated");
+    }
+
+    #[tokio::test]
+    async fn invalid_config_keeps_the_previous_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = Current::new(
+            dir.path().join("config.toml"),
+            test_support::parse(CONFIG, dir.path()),
+        );
+
+        write_config(dir.path(), &CONFIG.replace("s3cret", ""));
+        assert!(current.reload().is_err());
+        assert_eq!(current.load().config.clients["abc"].secret, "s3cret");
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Delivery ids seen recently, so a captured request replayed with its valid
+/// signature is not dispatched a second time.
+pub struct SeenDeliveries {
+    clock: Arc<dyn Clock>,
+    seen: Mutex<Seen>,
+}
+
+impl Default for SeenDeliveries {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashMap<String, Instant>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl SeenDeliveries {
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        SeenDeliveries {
+            clock,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Records `id`, false if it was already seen within `ttl`. Only the
+    /// newest `capacity` ids are remembered, 0 turns the check off.
+    pub fn insert(&self, id: &str, capacity: usize, ttl: Duration) -> bool {
+        if capacity == 0 {
+            return true;
+        }
+
+        let now = self.clock.now();
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((at, _)) = seen.order.front() {
+            if now.duration_since(*at) < ttl && seen.order.len() < capacity {
+                break;
+            }
+            seen.pop_oldest();
+        }
+
+        if seen.ids.contains_key(id) {
+            return false;
+        }
+        seen.ids.insert(id.to_string(), now);
+        seen.order.push_back((now, id.to_string()));
+        true
+    }
+
+    /// Whether `id` was seen within `ttl`, without recording it.
+    pub fn contains(&self, id: &str, ttl: Duration) -> bool {
+        let now = self.clock.now();
+        let seen = self.seen.lock().unwrap();
+        seen.ids
+            .get(id)
+            .is_some_and(|at| now.duration_since(*at) < ttl)
+    }
+
+    /// Lets a delivery that failed be retried with the same id.
+    pub fn forget(&self, id: &str) {
+        self.seen.lock().unwrap().ids.remove(id);
+    }
+}
+
+impl Seen {
+    fn pop_oldest(&mut self) {
+        if let Some((at, id)) = self.order.pop_front() {
+            // a forgotten and re-sent id has a newer entry further back
+            if self.ids.get(&id) == Some(&at) {
+                self.ids.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::clock::FakeClock;
+    use crate::test_support::{self, CONFIG};
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn replay_is_rejected_until_the_ttl_passes() {
+        let clock = Arc::new(FakeClock::new());
+        let seen = SeenDeliveries::with_clock(clock.clone());
+
+        assert!(seen.insert("d1", 10, TTL));
+        assert!(!seen.insert("d1", 10, TTL));
+        assert!(seen.contains("d1", TTL));
+
+        clock.advance(TTL);
+        assert!(!seen.contains("d1", TTL));
+        assert!(seen.insert("d1", 10, TTL));
+    }
+
+    #[test]
+    fn forgotten_delivery_can_be_retried() {
+        let seen = SeenDeliveries::with_clock(Arc::new(FakeClock::new()));
+
+        assert!(seen.insert("d1", 10, TTL));
+        seen.forget("d1");
+        assert!(seen.insert("d1", 10, TTL));
+    }
+
+    #[test]
+    fn only_the_newest_ids_are_kept() {
+        let seen = SeenDeliveries::with_clock(Arc::new(FakeClock::new()));
+
+        assert!(seen.insert("d1", 2, TTL));
+        assert!(seen.insert("d2", 2, TTL));
+        assert!(seen.insert("d3", 2, TTL));
+        assert!(seen.insert("d1", 2, TTL));
+    }
+
+    #[tokio::test]
+    async fn redelivery_is_answered_as_a_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+        let delivery = |mut req: Request<Body>| {
+            req.headers_mut()
+                .insert("X-GitHub-Delivery", "72d3162e".parse().unwrap());
+            req
+        };
+
+        let first = app
+            .clone()
+            .oneshot(delivery(test_support::signed("/deploy", "{}")))
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(lines.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let replayed = app
+            .oneshot(delivery(test_support::signed("/deploy", "{}")))
+            .await
+            .unwrap();
+        assert_eq!(replayed.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(replayed.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"duplicate":true}"#);
+        assert!(lines.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
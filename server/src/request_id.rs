@@ -0,0 +1,91 @@
+use axum::{
+    body::Body,
+    http::{header::HeaderName, Request},
+};
+use tower_http::request_id::{MakeRequestId, MakeRequestUuid, RequestId};
+use tracing::Span;
+
+use crate::auth::GITHUB_DELIVERY_HEADER;
+
+/// Set on the request before anything else sees it and echoed on the
+/// response, dispatch gets it with the command. A request already carrying
+/// one keeps it.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-webhook-request-id");
+
+/// GitHub's delivery id when there is one, so a redelivery can be found in
+/// the logs by the id GitHub shows, otherwise a fresh uuid.
+#[derive(Clone, Copy)]
+pub struct DeliveryOrUuid;
+
+impl MakeRequestId for DeliveryOrUuid {
+    fn make_request_id<B>(&mut self, request: &Request<B>) -> Option<RequestId> {
+        match request.headers().get(&GITHUB_DELIVERY_HEADER) {
+            Some(delivery) => Some(RequestId::new(delivery.clone())),
+            None => MakeRequestUuid.make_request_id(request),
+        }
+    }
+}
+
+/// The span every log line for a request is made in.
+pub fn make_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok());
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    #[tokio::test]
+    async fn response_carries_a_fresh_request_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        let res = app
+            .oneshot(test_support::signed("/deploy", "{}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let request_id = res.headers()[&REQUEST_ID_HEADER].to_str().unwrap();
+        // a hyphenated uuid
+        assert_eq!(request_id.len(), 36, "{request_id}");
+        assert_eq!(request_id.matches('-').count(), 4, "{request_id}");
+
+        let line = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        let command = config::Command::from_wire(&line).unwrap();
+        assert_eq!(command.request_id.as_deref(), Some(request_id));
+    }
+
+    #[tokio::test]
+    async fn delivery_id_is_echoed_as_the_request_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(CONFIG, dir.path());
+
+        let mut req = test_support::signed("/deploy", "{}");
+        req.headers_mut()
+            .insert(&GITHUB_DELIVERY_HEADER, "delivery-1".parse().unwrap());
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[&REQUEST_ID_HEADER], "delivery-1");
+
+        let line = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        let command = config::Command::from_wire(&line).unwrap();
+        assert_eq!(command.request_id.as_deref(), Some("delivery-1"));
+    }
+}
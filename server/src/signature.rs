@@ -0,0 +1,86 @@
+use std::fmt::Write;
+
+use config::SignatureAlgorithm;
+
+pub use config::signature::{expected_signature, matches};
+
+/// Finds which common proxy mangling of the body, if undone, would make
+/// `signature` match. Only for diagnosing failures, a match here must never be
+/// treated as an authenticated request.
+pub fn diagnose_mismatch(
+    algorithm: SignatureAlgorithm,
+    secret: &str,
+    body: &[u8],
+    signature: &str,
+) -> Option<&'static str> {
+    let mut variants: Vec<(&'static str, Vec<u8>)> = Vec::new();
+
+    match body.strip_suffix(b"\n") {
+        Some(stripped) => variants.push(("trailing newline removed", stripped.to_vec())),
+        None => variants.push(("trailing newline added", [body, b"\n"].concat())),
+    }
+
+    if body.windows(2).any(|w| w == b"\r\n") {
+        variants.push(("crlf converted to lf", replace(body, b"\r\n", b"\n")));
+    } else if body.contains(&b'\n') {
+        variants.push(("lf converted to crlf", replace(body, b"\n", b"\r\n")));
+    }
+
+    variants
+        .into_iter()
+        .find(|(_, variant)| expected_signature(algorithm, secret, variant) == signature)
+        .map(|(name, _)| name)
+}
+
+fn replace(body: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut rest = body;
+    while !rest.is_empty() {
+        if rest.starts_with(from) {
+            out.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            out.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    out
+}
+
+const FINGERPRINT_SALT: &[u8] = b"webhooks secret fingerprint v1";
+
+/// A short, salted digest of a secret that is safe to log so operators can
+/// check that hosts share the same secret. Only 32 bits of the digest are
+/// kept, far too few to recover the secret from.
+pub fn secret_fingerprint(secret: &str) -> String {
+    let digest = hmac_sha256::HMAC::mac(secret.as_bytes(), FINGERPRINT_SALT);
+    digest[..4].iter().fold(String::new(), |mut acc, n| {
+        let _ = write!(acc, "{:02x}", n);
+        acc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_is_stable_and_short() {
+        let fingerprint = secret_fingerprint("s3cret");
+        assert_eq!(fingerprint, secret_fingerprint("s3cret"));
+        assert_eq!(fingerprint.len(), 8);
+        assert!(fingerprint.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn fingerprint_is_salted() {
+        assert_ne!(secret_fingerprint("s3cret"), secret_fingerprint("s3cres"));
+
+        let unsalted = hmac_sha256::Hash::hash(b"s3cret");
+        let unsalted = unsalted.iter().fold(String::new(), |mut acc, n| {
+            let _ = write!(acc, "{:02x}", n);
+            acc
+        });
+        assert!(!unsalted.starts_with(&secret_fingerprint("s3cret")));
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+
+use opentelemetry::{
+    propagation::{Injector, TextMapPropagator},
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Exports spans over OTLP/HTTP to `endpoint`, the collector's full traces URL.
+pub fn layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, trace::Tracer>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_http_client(reqwest::Client::new())
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::Config::default()
+                .with_resource(Resource::new([KeyValue::new("service.name", "webhooks")])),
+        )
+        .install_batch(runtime::Tokio)?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(provider.tracer("server")))
+}
+
+/// The current span's context in W3C `traceparent` form, for the dispatch
+/// binary to continue the trace from.
+pub fn current_context() -> BTreeMap<String, String> {
+    let mut carrier = Carrier(BTreeMap::new());
+    TraceContextPropagator::new().inject_context(&tracing::Span::current().context(), &mut carrier);
+    carrier.0
+}
+
+struct Carrier(BTreeMap<String, String>);
+
+impl Injector for Carrier {
+    fn set(&mut self, key: &str, value: String) {
+        if !value.is_empty() {
+            self.0.insert(key.to_string(), value);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+
+use axum::body::Body;
+use axum::http::Request;
+use axum::Router;
+
+use config::{Config, ConfigFormat, SignatureAlgorithm};
+
+use crate::reload::Current;
+
+/// One client, `abc` with the secret `s3cret`, allowed to deploy `proj`.
+/// `{dir}` is replaced with a directory the test owns.
+pub const CONFIG: &str = r#"
+version = 1
+
+[webhooks]
+pipe = "{dir}/pipe"
+listen_addr = "127.0.0.1"
+listen_port = 0
+
+[dispatch]
+pipe = "{dir}/pipe"
+scripts_dir = "{dir}/scripts"
+
+[clients.abc]
+secret = "s3cret"
+project = "proj"
+permissions = ["deploy"]
+"#;
+
+/// Parses and validates `toml` with `{dir}` replaced.
+pub fn parse(toml: &str, dir: &Path) -> Config {
+    let toml = toml.replace("{dir}", &dir.display().to_string());
+    let config = Config::parse(&toml, ConfigFormat::Toml).expect("test config parses");
+    config.validate().expect("test config is valid");
+    config
+}
+
+/// Leaked like the server's own config.
+pub fn config(toml: &str, dir: &Path) -> &'static Config {
+    Box::leak(Box::new(parse(toml, dir)))
+}
+
+/// The server's routes and layers for the config.
+pub fn app(toml: &str, dir: &Path) -> Router {
+    let current = Current::new(dir.join("config.toml"), parse(toml, dir));
+    crate::app(Arc::new(current))
+}
+
+/// A POST to `uri` from `abc`, signed with its secret.
+pub fn signed(uri: &str, body: &str) -> Request<Body> {
+    let signature = config::signature::expected_signature(
+        SignatureAlgorithm::Sha256,
+        "s3cret",
+        body.as_bytes(),
+    );
+    Request::post(uri)
+        .header("Authorization", "Bearer abc")
+        .header("X-Hub-Signature-256", signature)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Makes the fifo at `{dir}/pipe` and reads it like dispatch would, handing
+/// back each line written.
+pub fn pipe_reader(dir: &Path) -> mpsc::Receiver<String> {
+    let pipe = dir.join("pipe");
+    mkfifo(&pipe);
+
+    let (lines, received) = mpsc::channel();
+    std::thread::spawn(move || loop {
+        let file = match std::fs::File::open(&pipe) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        for line in BufReader::new(file).lines() {
+            if lines.send(line.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    received
+}
+
+/// Makes a fifo at `path`.
+pub fn mkfifo(path: &Path) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+}
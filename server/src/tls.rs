@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use axum_server::{
+    accept::{Accept, DefaultAcceptor},
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, NoClientAuth},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_http::add_extension::AddExtension;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use config::WebHookConfig;
+
+/// Common name and DNS subject alternative names from a verified client certificate.
+#[derive(Debug, Clone, Default)]
+pub struct PeerNames(pub Vec<String>);
+
+impl PeerNames {
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|n| n == name)
+    }
+}
+
+pub fn rustls_config(webhooks: &WebHookConfig) -> io::Result<Option<RustlsConfig>> {
+    let (cert, key) = match (&webhooks.tls_cert, &webhooks.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => {
+            if webhooks.client_ca.is_some() {
+                return Err(invalid("client_ca requires tls_cert and tls_key"));
+            }
+            return Ok(None);
+        }
+        _ => return Err(invalid("tls_cert and tls_key must be set together")),
+    };
+
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &webhooks.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| invalid(format!("invalid client ca: {err}")))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_client_cert_verifier(NoClientAuth::new()),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(format!("invalid certificate or key: {err}")))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Some(RustlsConfig::from_config(Arc::new(config))))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(invalid(format!("no private key in {}", path.display()))),
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+/// Performs the TLS handshake and attaches the client certificate's names to
+/// every request on the connection.
+#[derive(Clone)]
+pub struct PeerNamesAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl PeerNamesAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        PeerNamesAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for PeerNamesAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, PeerNames>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let handshake =
+            <RustlsAcceptor<DefaultAcceptor> as Accept<I, S>>::accept(&self.inner, stream, service);
+
+        Box::pin(async move {
+            let (stream, service) = handshake.await?;
+            let names = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| peer_names(&cert.0))
+                .unwrap_or_default();
+
+            Ok((stream, AddExtension::new(service, names)))
+        })
+    }
+}
+
+fn peer_names(der: &[u8]) -> PeerNames {
+    let cert = match X509Certificate::from_der(der) {
+        Ok((_, cert)) => cert,
+        Err(_) => return PeerNames::default(),
+    };
+
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(String::from)
+        .collect();
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    PeerNames(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    /// Points at `testdata/self_signed.*`, made with `openssl req -x509` for
+    /// `CN=localhost`.
+    fn tls_config() -> String {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        CONFIG.replace(
+            "[dispatch]",
+            &format!(
+                "tls_cert = \"{}\"\ntls_key = \"{}\"\n\n[dispatch]",
+                testdata.join("self_signed.crt").display(),
+                testdata.join("self_signed.key").display(),
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn self_signed_cert_builds_the_acceptor() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::parse(&tls_config(), dir.path());
+
+        let rustls = rustls_config(&config.webhooks)
+            .expect("cert and key load")
+            .expect("tls is configured");
+        PeerNamesAcceptor::new(rustls);
+    }
+
+    #[test]
+    fn missing_key_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::parse(&tls_config(), dir.path());
+        config.webhooks.tls_key = Some(dir.path().join("missing.key"));
+
+        assert!(rustls_config(&config.webhooks).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    http::{self, HeaderMap, StatusCode},
+    Extension,
+};
+use serde::Deserialize;
+
+use config::{signature, Action, Config};
+
+use crate::dispatcher::Dispatched;
+use crate::error::ApiError;
+use crate::reload::Current;
+
+#[derive(Deserialize)]
+struct TriggerBody {
+    action: String,
+    project: String,
+}
+
+/// Dispatches `{"action", "project"}` for an operator holding the
+/// `admin_secret` as a bearer token, no client or signature involved. Not
+/// found while no admin secret is configured.
+pub async fn trigger(
+    Extension(current): Extension<Arc<Current>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Dispatched, ApiError> {
+    let runtime = current.load();
+    authorize_admin(runtime.config, &headers)?;
+
+    let body: TriggerBody = serde_json::from_slice(&body).map_err(|_| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_body",
+            r#"expected a body of {"action": ..., "project": ...}"#,
+        )
+    })?;
+    let action: Action = body.action.parse().map_err(|_| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unknown_action",
+            "action is not a known action",
+        )
+        .with_detail("action", &body.action)
+    })?;
+    if !runtime.config.has_project(&body.project) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unknown_project",
+            "project is not configured",
+        )
+        .with_detail("project", &body.project));
+    }
+
+    tracing::info!("received manual {action} trigger for {}", body.project);
+    Ok(runtime.dispatcher.trigger(&body.project, action).await?)
+}
+
+/// Admin endpoints are not found while no admin secret is configured, and
+/// otherwise need it as a bearer token.
+pub fn authorize_admin(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    let admin_secret = config
+        .webhooks
+        .admin_secret
+        .as_deref()
+        .ok_or_else(ApiError::not_found)?;
+
+    let token = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| signature::token_matches(admin_secret, token.trim())) {
+        tracing::info!("rejecting admin request with a bad admin token");
+        return Err(ApiError::unauthorized());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    fn trigger(token: &str, body: &str) -> Request<Body> {
+        Request::post("/trigger")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn admin_config() -> String {
+        CONFIG.replace("[dispatch]", "admin_secret = \"adm1n\"\n\n[dispatch]")
+    }
+
+    #[tokio::test]
+    async fn trigger_dispatches_the_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = test_support::pipe_reader(dir.path());
+        let app = test_support::app(&admin_config(), dir.path());
+
+        let body = r#"{"action": "rollback", "project": "proj"}"#;
+        let res = app.oneshot(trigger("adm1n", body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let line = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        let command = config::Command::from_wire(&line).unwrap();
+        assert_eq!(command.to_string(), "rollback proj");
+    }
+
+    #[tokio::test]
+    async fn bad_admin_token_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(&admin_config(), dir.path());
+
+        let body = r#"{"action": "deploy", "project": "proj"}"#;
+        let res = app.clone().oneshot(trigger("s3cret", body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // without an admin secret there is no endpoint to authorize against
+        let app = test_support::app(CONFIG, dir.path());
+        let res = app.oneshot(trigger("adm1n", body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_project_or_action_is_a_bad_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_support::app(&admin_config(), dir.path());
+
+        for body in [
+            r#"{"action": "deploy", "project": "other"}"#,
+            r#"{"action": "explode", "project": "proj"}"#,
+            "not json",
+        ] {
+            let res = app.clone().oneshot(trigger("adm1n", body)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+        }
+    }
+}
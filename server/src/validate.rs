@@ -0,0 +1,271 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use config::startup::StartupError;
+use config::{Action, Config, REPO_PLACEHOLDER};
+
+use crate::{health, tls};
+
+/// What the server would start with and what it would find missing, logged
+/// at startup, printed as JSON by `--check` and as lines by `validate`.
+#[derive(Serialize)]
+pub struct SelfCheck<'a> {
+    scripts_dirs: &'a [PathBuf],
+    clients: Vec<ClientCheck<'a>>,
+    pipe: PipeCheck<'a>,
+    tls: String,
+    /// Missing script directories and TLS errors. An unwritable pipe is not
+    /// counted, dispatch may simply not be running yet.
+    problems: usize,
+}
+
+#[derive(Serialize)]
+struct ClientCheck<'a> {
+    name: &'a str,
+    permissions: Vec<Action>,
+    projects: Vec<ProjectCheck<'a>>,
+}
+
+#[derive(Serialize)]
+struct ProjectCheck<'a> {
+    project: &'a str,
+    /// Where its scripts were found, None when they weren't or for a
+    /// `{repo}` template, which is checked per request.
+    scripts_dir: Option<PathBuf>,
+    found: bool,
+}
+
+#[derive(Serialize)]
+struct PipeCheck<'a> {
+    path: &'a Path,
+    writable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<'a> SelfCheck<'a> {
+    pub fn run(config: &'a Config) -> Self {
+        let mut problems = 0;
+
+        let mut names: Vec<&String> = config.clients.keys().collect();
+        names.sort();
+        let clients = names
+            .into_iter()
+            .map(|name| {
+                let client = &config.clients[name];
+                let mut permissions: Vec<Action> = client.permissions.iter().copied().collect();
+                permissions.sort();
+
+                let projects = client
+                    .projects
+                    .iter()
+                    .map(|project| {
+                        if project.contains(REPO_PLACEHOLDER) {
+                            return ProjectCheck {
+                                project,
+                                scripts_dir: None,
+                                found: true,
+                            };
+                        }
+                        let scripts_dir = config.dispatch.project_scripts_dir(project);
+                        if scripts_dir.is_none() {
+                            problems += 1;
+                        }
+                        ProjectCheck {
+                            project,
+                            found: scripts_dir.is_some(),
+                            scripts_dir,
+                        }
+                    })
+                    .collect();
+
+                ClientCheck {
+                    name,
+                    permissions,
+                    projects,
+                }
+            })
+            .collect();
+
+        let opened = health::open_pipe(&config.webhooks);
+        let pipe = PipeCheck {
+            path: &config.webhooks.pipe,
+            writable: opened.is_ok(),
+            error: opened.err().map(|err| err.to_string()),
+        };
+
+        let tls = match tls::rustls_config(&config.webhooks) {
+            Ok(Some(_)) => "ok".to_string(),
+            Ok(None) => "not configured".to_string(),
+            Err(err) => {
+                problems += 1;
+                err.to_string()
+            }
+        };
+
+        SelfCheck {
+            scripts_dirs: &config.dispatch.scripts_dirs,
+            clients,
+            pipe,
+            tls,
+            problems,
+        }
+    }
+
+    pub fn log(&self) {
+        tracing::info!("loaded {} clients", self.clients.len());
+        for client in self.clients.iter() {
+            let permissions: Vec<String> =
+                client.permissions.iter().map(Action::to_string).collect();
+            tracing::info!(
+                "client {} may {} for {} projects",
+                client.name,
+                permissions.join(", "),
+                client.projects.len()
+            );
+            for project in client.projects.iter().filter(|project| !project.found) {
+                tracing::warn!(
+                    "client {} project {} has no script directory in {}",
+                    client.name,
+                    project.project,
+                    self.roots()
+                );
+            }
+        }
+        match self.pipe.error.as_deref() {
+            None => tracing::info!("dispatch pipe {} is writable", self.pipe.path.display()),
+            Some(err) => tracing::warn!(
+                "dispatch pipe {} is not writable yet: {err}",
+                self.pipe.path.display()
+            ),
+        }
+    }
+
+    fn roots(&self) -> String {
+        let roots: Vec<String> = self
+            .scripts_dirs
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect();
+        roots.join(", ")
+    }
+
+    /// Fails when there were problems.
+    pub fn result(&self) -> Result<(), StartupError> {
+        match self.problems {
+            0 => Ok(()),
+            1 => Err(StartupError::ConfigValidation("found 1 problem".into())),
+            n => Err(StartupError::ConfigValidation(format!(
+                "found {n} problems"
+            ))),
+        }
+    }
+}
+
+/// Prints a line for each check, failing if any did. Loads everything the
+/// server would at startup but never binds a socket.
+pub fn validate(path: &Path) -> Result<(), StartupError> {
+    let config = Config::load(path)?;
+    println!("{}: ok", path.display());
+
+    let check = SelfCheck::run(&config);
+
+    let mut projects: Vec<&ProjectCheck> = check
+        .clients
+        .iter()
+        .flat_map(|client| client.projects.iter())
+        .collect();
+    projects.sort_unstable_by_key(|project| project.project);
+    projects.dedup_by_key(|project| project.project);
+    for project in projects {
+        let name = project.project;
+        match (project.scripts_dir.as_ref(), project.found) {
+            (Some(scripts_dir), _) => {
+                println!("project {name}: scripts in {}", scripts_dir.display())
+            }
+            (None, false) => println!("project {name}: no script directory in {}", check.roots()),
+            (None, true) => (),
+        }
+    }
+
+    println!("tls: {}", check.tls);
+    check.result()
+}
+
+/// Prints the self-check as JSON, failing if it found problems.
+pub fn check(path: &Path) -> Result<(), StartupError> {
+    let config = Config::load(path)?;
+    let check = SelfCheck::run(&config);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&check).expect("self-check is representable as json")
+    );
+    check.result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, CONFIG};
+
+    /// Writes `toml` as the config file, `{dir}` replaced with `dir`.
+    fn config_file(toml: &str, dir: &Path) -> PathBuf {
+        let path = dir.join("config.toml");
+        std::fs::write(&path, toml.replace("{dir}", &dir.display().to_string())).unwrap();
+        path
+    }
+
+    #[test]
+    fn good_config_validates() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("scripts/proj")).unwrap();
+
+        validate(&config_file(CONFIG, dir.path())).unwrap();
+    }
+
+    #[test]
+    fn bad_config_fails_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing_scripts = config_file(CONFIG, dir.path());
+        assert!(matches!(
+            validate(&missing_scripts),
+            Err(StartupError::ConfigValidation(_))
+        ));
+
+        std::fs::create_dir_all(dir.path().join("scripts/proj")).unwrap();
+        let toml = CONFIG.replace("secret = \"s3cret\"", "secret = \"\"");
+        assert!(matches!(
+            validate(&config_file(&toml, dir.path())),
+            Err(StartupError::ConfigValidation(_))
+        ));
+    }
+
+    #[test]
+    fn self_check_flags_a_missing_script_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("scripts/proj")).unwrap();
+        let toml = format!(
+            "{CONFIG}\n[clients.xyz]\nsecret = \"other\"\nproject = \"site\"\npermissions = [\"deploy\"]\n"
+        );
+        let config = test_support::parse(&toml, dir.path());
+
+        let check = serde_json::to_value(SelfCheck::run(&config)).unwrap();
+        assert_eq!(check["problems"], 1);
+        let projects: Vec<_> = check["clients"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|client| {
+                let project = &client["projects"][0];
+                (
+                    client["name"].as_str().unwrap(),
+                    project["project"].as_str().unwrap(),
+                    project["found"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(projects, [("abc", "proj", true), ("xyz", "site", false)]);
+        assert!(SelfCheck::run(&config).result().is_err());
+    }
+}